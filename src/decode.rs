@@ -0,0 +1,147 @@
+/*!
+ * Decompression of nImage part data.
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ */
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+
+use bzip2::read::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use lz4_flex::frame::FrameDecoder as Lz4Decoder;
+use xz2::bufread::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use super::format::CompMode;
+
+/// Constructor for a decompressing reader, given a reader of the compressed data
+type DecoderFn = for<'a> fn(Box<dyn BufRead + 'a>) -> io::Result<Box<dyn Read + 'a>>;
+
+/**
+ * A compression format which may be used in a CompMode::LibArchive part.
+ * The format is detected by the magic bytes at the start of the part data.
+ */
+pub struct Format {
+    /// short name of the format, for display
+    pub name: &'static str,
+    /// usual file extension for this format, without the dot
+    pub ext: &'static str,
+    /// magic bytes which identify this format
+    magic: &'static [u8],
+    /// create a decoder for this format
+    new_decoder: DecoderFn,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl fmt::Debug for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Format").field("name", &self.name).field("magic", &self.magic).finish()
+    }
+}
+
+/// Longest magic number in LIBARCHIVE_FORMATS, i.e. how many bytes detect() needs to see
+const MAGIC_MAX_LEN: usize = 6;
+
+/// list of compression formats supported for CompMode::LibArchive parts
+#[rustfmt::skip]
+pub static LIBARCHIVE_FORMATS: [Format; 5] = [
+    Format { name: "gzip", ext: "gz", magic: b"\x1f\x8b", new_decoder: |r| Ok(Box::new(MultiGzDecoder::new(r))) },
+    Format { name: "xz", ext: "xz", magic: b"\xfd7zXZ\x00", new_decoder: |r| Ok(Box::new(XzDecoder::new_multi_decoder(r))) },
+    Format { name: "bzip2", ext: "bz2", magic: b"BZh", new_decoder: |r| Ok(Box::new(MultiBzDecoder::new(r))) },
+    Format { name: "lz4", ext: "lz4", magic: b"\x04\x22\x4d\x18", new_decoder: |r| Ok(Box::new(Lz4Decoder::new(r))) },
+    Format { name: "zstd", ext: "zst", magic: b"\x28\xb5\x2f\xfd", new_decoder: |r| Ok(Box::new(ZstdDecoder::with_buffer(r)?)) },
+];
+
+/**
+ * Detect the compression format of data starting with buf. At least 6 bytes are needed to
+ * recognize every format. Returns None if the format isn't recognized.
+ */
+pub fn detect(buf: &[u8]) -> Option<&'static Format> {
+    LIBARCHIVE_FORMATS.iter().find(|f| buf.starts_with(f.magic))
+}
+
+/**
+ * Wrap a reader of (possibly compressed) part data in a reader which yields the decompressed
+ * data according to comp. For CompMode::None, the data is passed through unchanged.
+ * For CompMode::LibArchive, the format is detected from the first few bytes of data, and an
+ * InvalidData error is returned if it's not one of LIBARCHIVE_FORMATS.
+ */
+pub fn reader<'a, R: Read + 'a>(comp: CompMode, mut inner: R) -> io::Result<Box<dyn Read + 'a>> {
+    match comp {
+        CompMode::None => Ok(Box::new(inner)),
+        CompMode::Zstd => Ok(Box::new(ZstdDecoder::new(inner)?)),
+        CompMode::LibArchive => {
+            // read the first few bytes to look for a magic number, then stick them back on
+            // the front of the stream for the real decoder.
+            let mut head = Vec::with_capacity(MAGIC_MAX_LEN);
+            (&mut inner).take(MAGIC_MAX_LEN as u64).read_to_end(&mut head)?;
+            let format = detect(&head).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unrecognized compression format")
+            })?;
+            (format.new_decoder)(Box::new(BufReader::new(Cursor::new(head).chain(inner))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const DATA: &[u8] = b"\
+Lorem ipsum dolor sit amet, consectetur adipiscing elit. Pellentesque id dolor
+ut lorem rutrum pulvinar sed id augue. Pellentesque neque magna, dapibus eget
+congue pretium, suscipit nec eros. Vestibulum ipsum metus, efficitur vitae erat\n";
+
+    fn decode_all(comp: CompMode, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        reader(comp, data)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_libarchive_formats() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(DATA).unwrap();
+        let gz = gz.finish().unwrap();
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(DATA).unwrap();
+        let xz = xz.finish().unwrap();
+
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz.write_all(DATA).unwrap();
+        let bz = bz.finish().unwrap();
+
+        let mut lz4 = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        lz4.write_all(DATA).unwrap();
+        let lz4 = lz4.finish().unwrap();
+
+        let zst = zstd::encode_all(DATA, 3).unwrap();
+
+        for (name, data) in
+            [("gzip", gz), ("xz", xz), ("bzip2", bz), ("lz4", lz4), ("zstd", zst)].iter()
+        {
+            assert_eq!(detect(data).unwrap().name, *name);
+            assert_eq!(decode_all(CompMode::LibArchive, data).unwrap(), DATA, "format {}", name);
+        }
+    }
+
+    #[test]
+    fn test_passthrough_and_unknown() {
+        assert_eq!(decode_all(CompMode::None, DATA).unwrap(), DATA);
+        assert!(detect(DATA).is_none());
+        assert_eq!(
+            decode_all(CompMode::LibArchive, DATA).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // shorter than any magic number
+        assert!(decode_all(CompMode::LibArchive, b"BZ").is_err());
+    }
+}
@@ -0,0 +1,311 @@
+/*!
+ * Cryptographic digests of nImage parts and headers, plus hashing of part data.
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * nImage v3 only protects the header and part data with xxHash32, which catches corruption but
+ * not tampering. Starting with v4, the header names a digest algorithm, and every part header
+ * carries a digest of its data in addition to the xxHash32.
+ */
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hasher as _;
+use std::io::{self, Read, Write};
+
+use sha2::Digest as _;
+use twox_hash::XxHash32;
+
+use super::errors::*;
+use super::format::PartHeader;
+use super::util::to_hex;
+
+/// Size of every supported digest, in bytes
+pub const DIGEST_LEN: usize = 32;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DigestType {
+    /// SHA-256
+    Sha256 = 1,
+    /// BLAKE3 with 32 bytes of output, which is much faster than SHA-256 without SIMD help
+    Blake3,
+}
+// Safety! Keep this up to date
+const DIGEST_TYPE_FIRST: DigestType = DigestType::Sha256;
+const DIGEST_TYPE_LAST: DigestType = DigestType::Blake3;
+
+/// list of digest type names, used for Display and TryFrom<&str>
+#[rustfmt::skip]
+pub static DIGEST_TYPE_NAMES: [(DigestType, &str); 2] = [
+    (DigestType::Sha256, "sha256"),
+    (DigestType::Blake3, "blake3"),
+];
+
+impl Default for DigestType {
+    fn default() -> Self {
+        DigestType::Sha256
+    }
+}
+
+impl TryFrom<u8> for DigestType {
+    type Error = ImageValidError;
+    /**
+     * Convert a u8 into a DigestType, returning Err on an unrecognized type.
+     */
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        if val >= (DIGEST_TYPE_FIRST as u8) && val <= (DIGEST_TYPE_LAST as u8) {
+            // safe because DigestType is repr(u8) and we did a bounds check
+            Ok(unsafe { std::mem::transmute(val) })
+        } else {
+            Err(ImageValidError::BadDigestType(val))
+        }
+    }
+}
+
+impl TryFrom<&str> for DigestType {
+    type Error = ();
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        for (t, n) in DIGEST_TYPE_NAMES.iter() {
+            if name == *n {
+                return Ok(*t);
+            }
+        }
+        Err(())
+    }
+}
+
+impl fmt::Display for DigestType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (t, n) in DIGEST_TYPE_NAMES.iter() {
+            if self == t {
+                return f.write_str(n);
+            }
+        }
+        // if we get here, then DIGEST_TYPE_NAMES is messed up
+        panic!("Missing display name for DigestType {:?}", self);
+    }
+}
+
+/// A digest value along with the algorithm that produced it
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Digest {
+    pub dtype: DigestType,
+    pub bytes: [u8; DIGEST_LEN],
+}
+
+/// Digests are displayed as the algorithm name and hex value, e.g. "sha256:e3b0c442..."
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.dtype, to_hex(&self.bytes))
+    }
+}
+
+/// Incremental digest calculation for any DigestType
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(dtype: DigestType) -> Self {
+        match dtype {
+            DigestType::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            DigestType::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(buf),
+            Hasher::Blake3(h) => {
+                h.update(buf);
+            }
+        }
+    }
+
+    /// Get the digest of all data passed to update() so far
+    pub fn digest(&self) -> Digest {
+        let mut bytes = [0u8; DIGEST_LEN];
+        let dtype = match self {
+            Hasher::Sha256(h) => {
+                bytes.copy_from_slice(&h.clone().finalize());
+                DigestType::Sha256
+            }
+            Hasher::Blake3(h) => {
+                bytes.copy_from_slice(blake3::Hasher::finalize(h).as_bytes());
+                DigestType::Blake3
+            }
+        };
+        Digest { dtype, bytes }
+    }
+}
+
+/**
+ * One-off digest of a byte slice.
+ */
+pub fn digest(dtype: DigestType, buf: &[u8]) -> Digest {
+    let mut hasher = Hasher::new(dtype);
+    hasher.update(buf);
+    hasher.digest()
+}
+
+/**
+ * Calculates everything needed to verify a part's data: the size, the xxHash32, and the
+ * digest if the image has one. This works the same way for every image version, so tools
+ * don't need to care whether the image has digests or not.
+ */
+pub struct PartHasher {
+    xxh: XxHash32,
+    digest: Option<Hasher>,
+}
+
+impl PartHasher {
+    /**
+     * Create a new PartHasher which calculates a digest of the given type, if any,
+     * along with the xxHash32.
+     */
+    pub fn new(dtype: Option<DigestType>) -> Self {
+        PartHasher { xxh: XxHash32::with_seed(0), digest: dtype.map(Hasher::new) }
+    }
+
+    /// Create a new PartHasher with the right digest type to verify part
+    pub fn for_part(part: &PartHeader) -> Self {
+        Self::new(part.digest.map(|d| d.dtype))
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        self.xxh.write(buf);
+        if let Some(digest) = &mut self.digest {
+            digest.update(buf);
+        }
+    }
+
+    /// Get the total number of bytes hashed so far
+    pub fn total_len(&self) -> u64 {
+        self.xxh.total_len_64()
+    }
+
+    /// Get the xxHash32 of all data so far
+    pub fn xxh(&self) -> u32 {
+        self.xxh.finish() as u32
+    }
+
+    /// Get the digest of all data so far, if we're calculating one
+    pub fn digest(&self) -> Option<Digest> {
+        self.digest.as_ref().map(Hasher::digest)
+    }
+
+    /**
+     * Check the xxHash32 and digest of the data against a part header. The size isn't
+     * checked here, since callers generally want to report truncated data differently.
+     */
+    pub fn verify(&self, part: &PartHeader) -> PartValidResult<()> {
+        let xxh = self.xxh();
+        if xxh != part.xxh {
+            return Err(PartValidError::BadHash { expected: part.xxh, actual: xxh });
+        }
+        match (part.digest, self.digest()) {
+            (Some(expected), Some(actual)) if expected != actual => {
+                Err(PartValidError::BadDigest { expected, actual })
+            }
+            // a hasher from new(None) can't check a part that has a digest
+            (Some(_), None) => Err(PartValidError::MissingDigestType),
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Wrap a reader so that everything read from it is hashed.
+     */
+    pub fn reader<R: Read>(&mut self, inner: R) -> HashReader<'_, R> {
+        HashReader { inner, hasher: self }
+    }
+}
+
+impl Write for PartHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader which passes all data read into a PartHasher, see PartHasher::reader()
+pub struct HashReader<'a, R> {
+    inner: R,
+    hasher: &'a mut PartHasher,
+}
+
+impl<'a, R> HashReader<'a, R> {
+    /// Access the hasher, e.g. to check the progress so far
+    pub fn hasher(&self) -> &PartHasher {
+        self.hasher
+    }
+}
+
+impl<'a, R: Read> Read for HashReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_matches;
+
+    #[test]
+    fn test_digests() {
+        assert_eq!(
+            digest(DigestType::Sha256, b"abc").to_string(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest(DigestType::Blake3, b"abc").to_string(),
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(DigestType::try_from("blake3"), Ok(DigestType::Blake3));
+        assert_eq!(DigestType::try_from(0u8), Err(ImageValidError::BadDigestType(0)));
+    }
+
+    #[test]
+    fn test_part_hasher() {
+        let data = b"some part data";
+        let mut part = PartHeader {
+            size: data.len() as u64,
+            xxh: crate::xxhio::xxhash32(data),
+            digest: Some(digest(DigestType::Blake3, data)),
+            ..PartHeader::default()
+        };
+
+        let mut hasher = PartHasher::for_part(&part);
+        io::copy(&mut hasher.reader(&data[..]), &mut io::sink()).unwrap();
+        assert_eq!(hasher.total_len(), part.size);
+        assert_eq!(hasher.verify(&part), Ok(()));
+
+        // the xxHash can still match when the digest doesn't
+        let mut bad = part.digest.unwrap();
+        bad.bytes[0] ^= 1;
+        part.digest = Some(bad);
+        assert_matches!(hasher.verify(&part), Err(PartValidError::BadDigest { .. }));
+
+        // a hasher that isn't calculating the digest can't verify it
+        let mut hasher = PartHasher::new(None);
+        hasher.update(data);
+        assert_eq!(hasher.verify(&part), Err(PartValidError::MissingDigestType));
+
+        // without a digest, only the xxHash is checked
+        part.digest = None;
+        let mut hasher = PartHasher::for_part(&part);
+        hasher.write_all(data).unwrap();
+        assert_eq!(hasher.digest(), None);
+        assert_eq!(hasher.verify(&part), Ok(()));
+    }
+}
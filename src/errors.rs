@@ -7,18 +7,22 @@
 use std::error::Error;
 use std::fmt;
 
+use super::digest::{Digest, DigestType};
 use super::format::*;
 
 /// Errors that may be seen when parsing/validating an nImage header
 #[derive(Debug, Eq, PartialEq)]
 pub enum ImageValidError {
-    BadSize(usize),
+    BadSize { expected: usize, actual: usize },
     BadMagic(u64),
     UnsupportedVersion(u8),
     NameTooLong(usize),
     TooManyParts(usize),
     InvalidPart { index: usize, err: PartValidError },
     BadHash { expected: u32, actual: u32 },
+    BadDigestType(u8),
+    MissingDigestType,
+    BadDigest { expected: Digest, actual: Digest },
 }
 
 pub type ImageValidResult<T> = Result<T, ImageValidError>;
@@ -36,9 +40,9 @@ impl fmt::Display for ImageValidError {
     #[rustfmt::skip] // rustfmt mangles this, use manual consistent formatting
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::BadSize(size) => {
+            Self::BadSize { expected, actual } => {
                 write!(f, "bad nImage header size. Expected {}, found {}",
-                       NIMG_HDR_SIZE, size)
+                       expected, actual)
             }
             Self::BadMagic(magic) => {
                 write!(f, "bad nImage magic. Expected 0x{:016x}, found 0x{:016x}",
@@ -60,6 +64,16 @@ impl fmt::Display for ImageValidError {
                 write!(f, "invalid image header hash. Expected 0x{:08x}, found 0x{:08x}",
                        expected, actual)
             }
+            Self::BadDigestType(t) => {
+                write!(f, "bad nImage digest type {}", t)
+            }
+            Self::MissingDigestType => {
+                write!(f, "missing nImage digest type")
+            }
+            Self::BadDigest { expected, actual } => {
+                write!(f, "invalid image header digest. Expected {}, found {}",
+                       expected, actual)
+            }
         }
     }
 }
@@ -67,11 +81,15 @@ impl fmt::Display for ImageValidError {
 /// Errors that may be seen when parsing/validating an nImage part header
 #[derive(Debug, Eq, PartialEq)]
 pub enum PartValidError {
-    BadSize(usize),
+    BadSize { expected: usize, actual: usize },
     BadMagic(u64),
     BadType(u8),
     BadComp(u8),
     BadHash { expected: u32, actual: u32 },
+    MissingDigest(DigestType),
+    MissingDigestType,
+    BadDigest { expected: Digest, actual: Digest },
+    TooLarge { offset: u64, size: u64 },
 }
 
 pub type PartValidResult<T> = Result<T, PartValidError>;
@@ -82,9 +100,9 @@ impl fmt::Display for PartValidError {
     #[rustfmt::skip] // rustfmt mangles this, use manual consistent formatting
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::BadSize(size) => {
+            Self::BadSize { expected, actual } => {
                 write!(f, "bad nImage part header size. Expected {}, found {}",
-                       NIMG_PHDR_SIZE, size)
+                       expected, actual)
             }
             Self::BadMagic(magic) => {
                 write!(f, "bad nImage part magic. Expected 0x{:016x}, actual 0x{:016x}",
@@ -100,6 +118,20 @@ impl fmt::Display for PartValidError {
                 write!(f, "invalid part data hash. Expected 0x{:08x}, found 0x{:08x}",
                        expected, actual)
             }
+            Self::MissingDigest(t) => {
+                write!(f, "missing {} part data digest", t)
+            }
+            Self::MissingDigestType => {
+                write!(f, "missing digest type for part data digest")
+            }
+            Self::BadDigest { expected, actual } => {
+                write!(f, "invalid part data digest. Expected {}, found {}",
+                       expected, actual)
+            }
+            Self::TooLarge { offset, size } => {
+                write!(f, "part offset {} and size {} exceed the maximum image size",
+                       offset, size)
+            }
         }
     }
 }
@@ -6,8 +6,9 @@
 
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
-use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
+use super::digest::{self, Digest, DigestType, DIGEST_LEN};
 use super::errors::*;
 use super::util::*;
 use super::xxhio;
@@ -19,20 +20,79 @@ pub const NIMG_HDR_MAGIC: u64 = 0x474D4953_4257454E_u64;
 pub const NIMG_PHDR_MAGIC: u64 = 0x54524150_474d494e_u64;
 
 /// Current (latest) version of the nImage format supported by this code
-pub const NIMG_CURRENT_VERSION: u8 = 3;
+pub const NIMG_CURRENT_VERSION: u8 = 4;
 
-/// Size of the nImage header
+/// Oldest version of the nImage format supported by this code
+pub const NIMG_MIN_VERSION: u8 = 3;
+
+/// Size of the v3 nImage header. No header version is smaller than this, so reading this many
+/// bytes is always enough to find the version and the full header size.
 pub const NIMG_HDR_SIZE: usize = 1024;
 
-/// Size of each nImage part header
+/// Size of the v4 nImage header
+pub const NIMG_V4_HDR_SIZE: usize = 2048;
+
+/// Largest end offset of a part's data. Parsing rejects anything bigger, so that adding the
+/// header size and padding to it can't overflow. Real images are nowhere near this size.
+pub const NIMG_MAX_DATA_SIZE: u64 = i64::MAX as u64;
+
+/// Size of each v3 nImage part header
 pub const NIMG_PHDR_SIZE: usize = 32;
 
+/// Size of each v4 nImage part header
+pub const NIMG_V4_PHDR_SIZE: usize = 64;
+
 /// Max length (in bytes without a null-terminator) of the nImage name field
 pub const NIMG_NAME_LEN: usize = 128;
 
 /// Max number of parts in an image
 pub const NIMG_MAX_PARTS: usize = 27;
 
+/// Part data is padded to start on a multiple of this many bytes
+pub const PART_ALIGN: u64 = 16;
+
+/// Number of zero padding bytes that go after a part which ends at this offset
+pub fn part_padding(end: u64) -> u64 {
+    (PART_ALIGN - (end % PART_ALIGN)) % PART_ALIGN
+}
+
+/**
+ * The parts of the on-disk header layout which vary between format versions. Parsing and
+ * serialization dispatch on this rather than on version numbers directly, so supporting a new
+ * version means adding it to Layout::for_version and handling any new features it has.
+ */
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Layout {
+    /// size of the image header
+    pub header_size: usize,
+    /// size of each part header
+    pub part_header_size: usize,
+    /// whether the header has a digest type, a digest of each part, and ends with a digest
+    /// rather than an xxHash32
+    pub has_digests: bool,
+}
+
+impl Layout {
+    /**
+     * Get the layout for a format version, or None if the version isn't supported.
+     */
+    pub fn for_version(version: u8) -> Option<Self> {
+        match version {
+            3 => Some(Layout {
+                header_size: NIMG_HDR_SIZE,
+                part_header_size: NIMG_PHDR_SIZE,
+                has_digests: false,
+            }),
+            4 => Some(Layout {
+                header_size: NIMG_V4_HDR_SIZE,
+                part_header_size: NIMG_V4_PHDR_SIZE,
+                has_digests: true,
+            }),
+            _ => None,
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum PartType {
@@ -194,27 +254,53 @@ impl fmt::Display for CompMode {
  * struct that can be directly read from the file, but that's not so in Rust.
  * This parsed representation of the header omits the magic string and unused
  * fields, containing only data that matters.
+ *
+ * The same struct represents every supported format version, fields which don't exist
+ * in a version are None.
  */
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ImageHeader {
     // 8 byte magic "NEWBSIMG"
-    /// nImage format version
+    /// nImage format version, as read from disk or to be written by write_to()
     pub version: u8,
 
     // 1 byte number of parts in the image, up to NIMG_MAX_PARTS (in Rust as parts.len())
-    // 6 unused bytes
+    /// v4: digest algorithm for the part data and this header (1 byte). None for v3.
+    pub digest_type: Option<DigestType>,
+
+    // 5 unused bytes (6 in v3)
     /// name of the image, max NIMG_NAME_LEN (128) bytes
     pub name: String,
 
     /// vector of part headers, up to NIMG_MAX_PARTS (27)
     pub parts: Vec<PartHeader>,
-    // 12 unused bytes
-    // 4 byte xxHash32 checksum of the rest of the image header data
+    // v3: 12 unused bytes
+    //     4 byte xxHash32 checksum of the rest of the image header data
+    // v4: 144 unused bytes
+    //     32 byte digest of the rest of the image header data
 }
 
 impl Default for ImageHeader {
     fn default() -> Self {
-        ImageHeader { version: NIMG_CURRENT_VERSION, name: String::new(), parts: Vec::new() }
+        ImageHeader::new("")
+    }
+}
+
+/// The checksum at the end of a raw image header, which isn't saved in ImageHeader itself
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HeaderChecksum {
+    /// v3 xxHash32
+    Xxh32(u32),
+    /// v4 digest
+    Digest(Digest),
+}
+
+impl fmt::Display for HeaderChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderChecksum::Xxh32(xxh) => write!(f, "0x{:08x}", xxh),
+            HeaderChecksum::Digest(digest) => digest.fmt(f),
+        }
     }
 }
 
@@ -240,6 +326,10 @@ pub struct PartHeader {
     // 2 unused bytes
     /// 4 byte xxHash32 checksum of the image data
     pub xxh: u32,
+
+    /// v4: 32 byte digest of the image data, using the image header's digest_type.
+    /// None for v3.
+    pub digest: Option<Digest>,
 }
 
 impl ImageHeader {
@@ -249,22 +339,89 @@ impl ImageHeader {
     pub fn new(name: &str) -> Self {
         ImageHeader {
             version: NIMG_CURRENT_VERSION,
+            digest_type: Some(DigestType::default()),
             name: String::from(name), // could probably be fancy and use Cow
             parts: Vec::new(),
         }
     }
 
     /**
-     * Parse and validate an nImage header read from disk.
-     * Data must be exactly NIMG_HDR_SIZE (1024) bytes long.
+     * On-disk layout of this header, which depends on the version.
+     * Panics if the version isn't supported, see validate().
+     */
+    pub fn layout(&self) -> Layout {
+        Layout::for_version(self.version).expect("unsupported nImage version")
+    }
+
+    /**
+     * Size of this header on disk, which depends on the version.
+     * Panics if the version isn't supported, see validate().
+     */
+    pub fn header_size(&self) -> usize {
+        self.layout().header_size
+    }
+
+    /**
+     * Size of the part data, from the end of the header to the end of the last part. This
+     * doesn't include the padding which usually follows the last part. For a parsed header,
+     * this is at most NIMG_MAX_DATA_SIZE.
+     */
+    pub fn data_size(&self) -> u64 {
+        self.parts.iter().map(|p| p.offset + p.size).max().unwrap_or(0)
+    }
+
+    /**
+     * Expected size of the whole image file as written by mknImage, i.e. the header plus
+     * data_size() plus padding after the last part. The padding isn't needed to read the
+     * image, so the file may be up to PART_ALIGN - 1 bytes shorter than this.
+     * Panics if the version isn't supported, see validate().
+     */
+    pub fn image_size(&self) -> u64 {
+        let data_size = self.data_size();
+        self.header_size() as u64 + data_size + part_padding(data_size)
+    }
+
+    /**
+     * Read a raw nImage header of any version, without parsing or validating anything beyond
+     * the magic and version needed to know how many bytes to read. This is useful for checking
+     * a signature before trusting anything in the header.
+     */
+    pub fn read_raw<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; NIMG_HDR_SIZE];
+        reader.read_exact(&mut buf)?;
+        let magic = u64::from_le_bytes(buf[..8].try_into().unwrap());
+        if magic == NIMG_HDR_MAGIC {
+            if let Some(layout) = Layout::for_version(buf[8]) {
+                buf.resize(layout.header_size, 0);
+                reader.read_exact(&mut buf[NIMG_HDR_SIZE..])?;
+            }
+        }
+        Ok(buf)
+    }
+
+    /**
+     * Read, parse, and validate an nImage header of any version. Returns the header along
+     * with the raw header bytes, which are needed to get the checksum or check a signature.
+     */
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<(Self, Vec<u8>)> {
+        let buf = Self::read_raw(reader)?;
+        let header =
+            Self::from_bytes(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((header, buf))
+    }
+
+    /**
+     * Parse and validate an nImage header of any supported version read from disk.
+     * Data must be exactly the header size for its version, NIMG_HDR_SIZE (1024) bytes for
+     * v3 or NIMG_V4_HDR_SIZE (2048) bytes for v4. The version is saved in the returned
+     * header, so that it can be written back out in the same format.
      * Relevant data will be copied out of buf, thus the returned object has no
      * lifetime restrictions.
      */
     pub fn from_bytes(buf: &[u8]) -> ImageValidResult<Self> {
-        // Ensure that the data is exactly the right size. This way we know that reading
-        // all the fields will never error (as long as this function has no bugs)
-        if buf.len() != NIMG_HDR_SIZE {
-            return Err(ImageValidError::BadSize(buf.len()));
+        // No version is smaller than v3, make sure that we can read the magic and version
+        if buf.len() < NIMG_HDR_SIZE {
+            return Err(ImageValidError::BadSize { expected: NIMG_HDR_SIZE, actual: buf.len() });
         }
 
         let mut header = ImageHeader::new("");
@@ -276,21 +433,15 @@ impl ImageHeader {
             return Err(ImageValidError::BadMagic(magic));
         }
 
-        // validate the hash
-        // seek to the last 4 bytes where the hash is
-        reader.seek(SeekFrom::End(-4)).unwrap();
-        let expected_xxh = reader.read_u32_le().unwrap();
-        let actual_xxh = xxhio::xxhash32(&buf[..(NIMG_HDR_SIZE - 4)]);
-        if expected_xxh != actual_xxh {
-            return Err(ImageValidError::BadHash { expected: expected_xxh, actual: actual_xxh });
-        }
-
-        // seek back to right after the magic
-        reader.seek(SeekFrom::Start(8)).unwrap();
-
         header.version = reader.read_byte().unwrap();
-        if header.version != NIMG_CURRENT_VERSION {
-            return Err(ImageValidError::UnsupportedVersion(header.version));
+        let layout = Layout::for_version(header.version)
+            .ok_or(ImageValidError::UnsupportedVersion(header.version))?;
+        let hdr_size = layout.header_size;
+
+        // Ensure that the data is exactly the right size. This way we know that reading
+        // all the fields will never error (as long as this function has no bugs)
+        if buf.len() != hdr_size {
+            return Err(ImageValidError::BadSize { expected: hdr_size, actual: buf.len() });
         }
 
         let num_parts = reader.read_byte().unwrap() as usize;
@@ -298,8 +449,33 @@ impl ImageHeader {
             return Err(ImageValidError::TooManyParts(num_parts));
         }
 
-        // 6 unused bytes
-        reader.skip(6);
+        // validate the hash or digest at the end of the header
+        header.digest_type = if layout.has_digests {
+            let dtype = DigestType::try_from(reader.read_byte().unwrap())?;
+            let expected =
+                Digest { dtype, bytes: buf[(hdr_size - DIGEST_LEN)..].try_into().unwrap() };
+            let actual = digest::digest(dtype, &buf[..(hdr_size - DIGEST_LEN)]);
+            if expected != actual {
+                return Err(ImageValidError::BadDigest { expected, actual });
+            }
+            // 5 unused bytes
+            reader.skip(5);
+            Some(dtype)
+        } else {
+            // seek to the last 4 bytes where the hash is
+            reader.seek(SeekFrom::End(-4)).unwrap();
+            let expected_xxh = reader.read_u32_le().unwrap();
+            let actual_xxh = xxhio::xxhash32(&buf[..(hdr_size - 4)]);
+            if expected_xxh != actual_xxh {
+                return Err(ImageValidError::BadHash {
+                    expected: expected_xxh,
+                    actual: actual_xxh,
+                });
+            }
+            // seek back to after the part count, and skip 6 unused bytes
+            reader.seek(SeekFrom::Start(16)).unwrap();
+            None
+        };
 
         // process the name, which is a 128 byte CString that may or may not be null-terminated.
         // CString::new doesn't want to see null bytes, so find and slice it ourself.
@@ -311,25 +487,41 @@ impl ImageHeader {
         header.name = String::from_utf8_lossy(&name[..nullpos]).into_owned();
 
         for pidx in 0..num_parts {
-            let phdr = reader.read_borrow(NIMG_PHDR_SIZE);
-            let phdr = PartHeader::from_bytes(phdr)
+            let phdr = reader.read_borrow(layout.part_header_size);
+            let phdr = PartHeader::from_bytes(phdr, header.version, header.digest_type)
                 .map_err(|err| ImageValidError::InvalidPart { index: pidx, err })?;
             header.parts.push(phdr);
         }
 
         // ignore everything after the last used part header:
         //  * empty part header slots
-        //  * 12 unused bytes
-        //  * 4 byte xxHash32 (already handled)
+        //  * unused bytes
+        //  * xxHash32 or digest (already handled)
         Ok(header)
     }
 
+    /**
+     * Get the checksum stored at the end of the raw bytes of this header. Panics if raw
+     * isn't long enough, it should be the data that this header was parsed from.
+     */
+    pub fn checksum(&self, raw: &[u8]) -> HeaderChecksum {
+        match self.digest_type {
+            Some(dtype) => HeaderChecksum::Digest(Digest {
+                dtype,
+                bytes: raw[(raw.len() - DIGEST_LEN)..].try_into().unwrap(),
+            }),
+            None => HeaderChecksum::Xxh32(u32::from_le_bytes(
+                raw[(raw.len() - 4)..].try_into().unwrap(),
+            )),
+        }
+    }
+
     /**
      * validate an nImage header before serialization,
      * i.e. that it has a valid name, version, and not too many parts
      */
     pub fn validate(&self) -> ImageValidResult<()> {
-        if self.version != NIMG_CURRENT_VERSION {
+        if Layout::for_version(self.version).is_none() {
             return Err(ImageValidError::UnsupportedVersion(self.version));
         }
         if self.name.len() > NIMG_NAME_LEN {
@@ -346,52 +538,92 @@ impl ImageHeader {
                 });
             }
         }
+        // v4 requires every part to have a digest. Earlier versions just don't write them.
+        if self.layout().has_digests {
+            let dtype = self.digest_type.ok_or(ImageValidError::MissingDigestType)?;
+            for (i, part) in self.parts.iter().enumerate() {
+                match part.digest {
+                    Some(digest) if digest.dtype == dtype => (),
+                    _ => {
+                        return Err(ImageValidError::InvalidPart {
+                            index: i,
+                            err: PartValidError::MissingDigest(dtype),
+                        })
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
     /**
-     * Serialize this image header into an array of bytes.
+     * Serialize this image header into an array of bytes, using the layout for self.version.
+     * To write an image that older versions of swdl can read, set version to an older
+     * version first. Any part digests are dropped when writing a version without them.
      */
-    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
         // validate ourselves, ensuring that the number of parts and name length won't overflow
         self.validate().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let layout = self.layout();
+        let hdr_size = layout.header_size;
+
+        // build the header in memory first, since the checksum covers all of it
+        let mut buf = Vec::with_capacity(hdr_size);
+        buf.write_u64_le(NIMG_HDR_MAGIC)?;
+        buf.write_byte(self.version)?;
+        buf.write_byte(self.parts.len().try_into().unwrap())?;
+        let digest_type = if layout.has_digests { self.digest_type } else { None };
+        match digest_type {
+            Some(dtype) => {
+                buf.write_byte(dtype as u8)?;
+                buf.write_zeros(5)?;
+            }
+            None => buf.write_zeros(6)?,
+        }
 
-        // wrap the writer to a xxhWriter which keeps track of the xxHash32 for everything written
-        let mut writer = xxhio::Writer::new(writer);
-
-        writer.write_u64_le(NIMG_HDR_MAGIC)?;
-        writer.write_byte(self.version)?;
-        writer.write_byte(self.parts.len().try_into().unwrap())?;
-        writer.write_zeros(6)?;
-
-        writer.write_all(self.name.as_bytes())?;
-        writer.write_zeros(NIMG_NAME_LEN - self.name.len())?;
+        buf.write_all(self.name.as_bytes())?;
+        buf.write_zeros(NIMG_NAME_LEN - self.name.len())?;
 
         for part in self.parts.iter() {
-            part.write_to(&mut writer)?;
+            part.write_to(&mut buf, self.version)?;
+        }
+        buf.write_zeros(layout.part_header_size * (NIMG_MAX_PARTS - self.parts.len()))?;
+
+        // unused bytes, then the xxHash32 or digest of everything before it
+        match digest_type {
+            Some(dtype) => {
+                buf.resize(hdr_size - DIGEST_LEN, 0);
+                let digest = digest::digest(dtype, &buf);
+                buf.write_all(&digest.bytes)?;
+            }
+            None => {
+                buf.resize(hdr_size - 4, 0);
+                let xxh = xxhio::xxhash32(&buf);
+                buf.write_u32_le(xxh)?;
+            }
         }
-        writer.write_zeros(NIMG_PHDR_SIZE * (NIMG_MAX_PARTS - self.parts.len()))?;
-        writer.write_zeros(12)?;
-
-        // get the xxHash32 of all the data written so far and unwrap the xxh writer
-        let xxh = writer.hash();
-        let mut writer = writer.into_inner();
-        writer.write_u32_le(xxh)?;
 
-        Ok(())
+        writer.write_all(&buf)
     }
 
     /**
-     * Print image header metadata to a writer. Optionally print the xxHash32 given here,
-     * e.g. extracted from the original image, since the hash isn't saved in ImageHeader itself.
+     * Print image header metadata to a writer. Optionally print the checksum given here,
+     * e.g. extracted from the original image, since it isn't saved in ImageHeader itself.
      */
-    pub fn print_to<W: Write>(&self, w: &mut W, xxh: Option<u32>) -> io::Result<()> {
+    pub fn print_to<W: Write>(
+        &self,
+        w: &mut W,
+        checksum: Option<&HeaderChecksum>,
+    ) -> io::Result<()> {
         let name = if self.name.is_empty() { "[empty]" } else { self.name.as_str() };
         writeln!(w, "Image Name:      {}", name)?;
         writeln!(w, "Image Version:   {}", self.version)?;
         writeln!(w, "Number of Parts: {}", self.parts.len())?;
-        if let Some(xxh) = xxh {
-            writeln!(w, "Header xxHash:   0x{:08x}", xxh)?;
+        writeln!(w, "Image Size:      {}", human_size_extended(self.image_size()))?;
+        match checksum {
+            Some(HeaderChecksum::Xxh32(xxh)) => writeln!(w, "Header xxHash:   0x{:08x}", xxh)?,
+            Some(HeaderChecksum::Digest(digest)) => writeln!(w, "Header Digest:   {}", digest)?,
+            None => (),
         }
 
         for (i, part) in self.parts.iter().enumerate() {
@@ -404,12 +636,21 @@ impl ImageHeader {
 
 impl PartHeader {
     /**
-     * Parse and validate an nImage part header read from disk.
-     * Data must be exactly NIMG_PHDR_SIZE (32) bytes long.
+     * Parse and validate an nImage part header read from disk, for an image of the given
+     * version and digest type. Data must be exactly the part header size for that version,
+     * NIMG_PHDR_SIZE (32) bytes for v3 or NIMG_V4_PHDR_SIZE (64) bytes for v4.
+     * Panics if the version isn't supported, ImageHeader::from_bytes() checks that first.
+     * Returns MissingDigestType if the version has digests but digest_type is None.
      */
-    pub fn from_bytes(buf: &[u8]) -> PartValidResult<Self> {
-        if buf.len() != NIMG_PHDR_SIZE {
-            return Err(PartValidError::BadSize(buf.len()));
+    pub fn from_bytes(
+        buf: &[u8],
+        version: u8,
+        digest_type: Option<DigestType>,
+    ) -> PartValidResult<Self> {
+        let layout = Layout::for_version(version).expect("unsupported nImage version");
+        let expected_size = layout.part_header_size;
+        if buf.len() != expected_size {
+            return Err(PartValidError::BadSize { expected: expected_size, actual: buf.len() });
         }
 
         let mut header = PartHeader::default();
@@ -422,20 +663,32 @@ impl PartHeader {
 
         header.size = reader.read_u64_le().unwrap();
         header.offset = reader.read_u64_le().unwrap();
+        match header.offset.checked_add(header.size) {
+            Some(end) if end <= NIMG_MAX_DATA_SIZE => (),
+            _ => return Err(PartValidError::TooLarge { offset: header.offset, size: header.size }),
+        }
         header.ptype = PartType::from_u8_valid(reader.read_byte().unwrap())?;
         header.comp = CompMode::try_from(reader.read_byte().unwrap())?;
 
         reader.skip(2);
         header.xxh = reader.read_u32_le().unwrap();
 
+        if layout.has_digests {
+            let dtype = digest_type.ok_or(PartValidError::MissingDigestType)?;
+            let bytes = reader.read_borrow(DIGEST_LEN).try_into().unwrap();
+            header.digest = Some(Digest { dtype, bytes });
+        }
+
         Ok(header)
     }
 
     /**
-     * Serialize this part header into a writer. On Success, exactly 32 bytes should
-     * have been written.
+     * Serialize this part header into a writer, for an image of the given version.
+     * On Success, exactly NIMG_PHDR_SIZE (v3) or NIMG_V4_PHDR_SIZE (v4) bytes should have
+     * been written. The digest must be set for v4, see ImageHeader::validate().
      */
-    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    pub fn write_to<W: Write>(&self, writer: &mut W, version: u8) -> io::Result<()> {
+        let layout = Layout::for_version(version).expect("unsupported nImage version");
         // use WriteHelper methods from util.rs, which are automatically implemented
         writer.write_u64_le(NIMG_PHDR_MAGIC)?;
         writer.write_u64_le(self.size)?;
@@ -444,6 +697,9 @@ impl PartHeader {
         writer.write_byte(self.comp as u8)?;
         writer.write_zeros(2)?;
         writer.write_u32_le(self.xxh)?;
+        if layout.has_digests {
+            writer.write_all(&self.digest.expect("v4 part header needs a digest").bytes)?;
+        }
         Ok(())
     }
 
@@ -458,6 +714,9 @@ impl PartHeader {
         writeln!(w, "{}size:        {}", indent, human_size_extended(self.size))?;
         writeln!(w, "{}offset:      {}", indent, human_size_extended(self.offset))?;
         writeln!(w, "{}xxHash:      0x{:08x}", indent, self.xxh)?;
+        if let Some(digest) = &self.digest {
+            writeln!(w, "{}digest:      {}", indent, digest)?;
+        }
         Ok(())
     }
 }
@@ -496,7 +755,8 @@ mod tests {
 
     fn good_header_obj() -> ImageHeader {
         ImageHeader {
-            version: NIMG_CURRENT_VERSION,
+            version: 3,
+            digest_type: None,
             name: String::from("2020-05-27-raspios-buster-lite-armhf"),
             parts: vec![
                 PartHeader {
@@ -505,6 +765,7 @@ mod tests {
                     ptype: PartType::BootImg,
                     comp: CompMode::Zstd,
                     xxh: 0xe74b8670,
+                    digest: None,
                 },
                 PartHeader {
                     size: 0x14235000,
@@ -512,6 +773,7 @@ mod tests {
                     ptype: PartType::Rootfs,
                     comp: CompMode::None,
                     xxh: 0xb6846841,
+                    digest: None,
                 },
             ],
         }
@@ -540,6 +802,31 @@ mod tests {
         assert_eq!(ImageHeader::from_bytes(&data).unwrap_err(), expected_err);
     }
 
+    #[test]
+    fn part_too_large() {
+        // the first part's offset, with the header hash fixed to match
+        let with_offset = |offset: u64| {
+            let mut data = good_header_bytes();
+            data[0xa0..0xa8].copy_from_slice(&offset.to_le_bytes());
+            let hash = xxhio::xxhash32(&data[..(NIMG_HDR_SIZE - 4)]);
+            data[(NIMG_HDR_SIZE - 4)..].copy_from_slice(&hash.to_le_bytes());
+            ImageHeader::from_bytes(&data)
+        };
+        let size = good_header_obj().parts[0].size;
+        for offset in [u64::MAX - 1, NIMG_MAX_DATA_SIZE - size + 1].iter() {
+            assert_eq!(
+                with_offset(*offset),
+                Err(ImageValidError::InvalidPart {
+                    index: 0,
+                    err: PartValidError::TooLarge { offset: *offset, size },
+                })
+            );
+        }
+        let header = with_offset(NIMG_MAX_DATA_SIZE - size).unwrap();
+        assert_eq!(header.data_size(), NIMG_MAX_DATA_SIZE);
+        header.image_size();
+    }
+
     #[test]
     fn write_image_header() {
         let header = good_header_obj();
@@ -552,4 +839,86 @@ mod tests {
 
         assert_eq!(arr.as_ref(), good_header_bytes().as_ref());
     }
+
+    #[test]
+    fn v4_image_header() {
+        let mut header = good_header_obj();
+        header.version = 4;
+        header.digest_type = Some(DigestType::Blake3);
+        // v4 needs a digest for every part
+        assert_eq!(
+            header.validate(),
+            Err(ImageValidError::InvalidPart {
+                index: 0,
+                err: PartValidError::MissingDigest(DigestType::Blake3)
+            })
+        );
+        for (i, part) in header.parts.iter_mut().enumerate() {
+            part.digest = Some(digest::digest(DigestType::Blake3, &[i as u8]));
+        }
+
+        let mut data = Vec::new();
+        header.write_to(&mut data).unwrap();
+        assert_eq!(data.len(), NIMG_V4_HDR_SIZE);
+        assert_eq!(&data[0x10..0x14], b"2020");
+
+        // read_from reads exactly one header, even with data after it
+        data.extend_from_slice(b"part data");
+        let mut reader = Cursor::new(&data);
+        let (parsed, raw) = ImageHeader::read_from(&mut reader).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(raw.len(), NIMG_V4_HDR_SIZE);
+        assert_eq!(reader.position(), NIMG_V4_HDR_SIZE as u64);
+        assert_eq!(
+            parsed.checksum(&raw),
+            HeaderChecksum::Digest(digest::digest(
+                DigestType::Blake3,
+                &raw[..(NIMG_V4_HDR_SIZE - DIGEST_LEN)]
+            ))
+        );
+
+        // any change to the header is caught by the digest
+        data[0x10] = b'3';
+        assert_matches!(
+            ImageHeader::from_bytes(&data[..NIMG_V4_HDR_SIZE]),
+            Err(ImageValidError::BadDigest { .. })
+        );
+        assert_eq!(
+            ImageHeader::from_bytes(&data[..NIMG_HDR_SIZE]),
+            Err(ImageValidError::BadSize { expected: NIMG_V4_HDR_SIZE, actual: NIMG_HDR_SIZE })
+        );
+
+        // a v4 part header can't be parsed without knowing the digest type
+        let mut phdr = Vec::new();
+        header.parts[0].write_to(&mut phdr, 4).unwrap();
+        assert_eq!(
+            PartHeader::from_bytes(&phdr, 4, Some(DigestType::Blake3)),
+            Ok(header.parts[0].clone())
+        );
+        assert_eq!(PartHeader::from_bytes(&phdr, 4, None), Err(PartValidError::MissingDigestType));
+
+        // writing the same header as v3 drops the digests
+        header.version = 3;
+        let mut data = Vec::new();
+        header.write_to(&mut data).unwrap();
+        assert_eq!(data, good_header_bytes().as_ref());
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        // the real v3 header with only the version byte changed. v1 and v2 have no known
+        // layout, so they're rejected rather than guessed at.
+        let mut header = good_header_obj();
+        for version in [0u8, 1, 2, NIMG_CURRENT_VERSION + 1].iter() {
+            header.version = *version;
+            assert_eq!(header.validate(), Err(ImageValidError::UnsupportedVersion(*version)));
+
+            let mut data = good_header_bytes();
+            data[8] = *version;
+            assert_eq!(
+                ImageHeader::from_bytes(&data),
+                Err(ImageValidError::UnsupportedVersion(*version))
+            );
+        }
+    }
 }
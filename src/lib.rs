@@ -10,7 +10,13 @@
 // [2] https://github.com/rust-lang/rust-clippy/pull/5419
 #![allow(clippy::unreadable_literal)]
 
+pub mod decode;
+pub mod digest;
 pub mod errors;
 pub mod format;
+pub mod lint;
+pub mod reader;
+pub mod sign;
 pub mod util;
+pub mod writer;
 pub mod xxhio;
@@ -0,0 +1,409 @@
+/*!
+ * Structural checks of complete nImage files, which report every problem they find.
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * ImageHeader::from_bytes and ImageReader stop at the first problem, which is what swdl wants.
+ * When figuring out what's wrong with an image, it's more useful to see everything at once, so
+ * lint_image() keeps going and collects each problem into a LintReport. Problems which would
+ * make swdl reject the image are errors, anything else that's suspicious is a warning.
+ */
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::io::{self, Read};
+
+use super::digest::{self, DigestType, PartHasher, DIGEST_LEN};
+use super::format::*;
+use super::reader::DEFAULT_MAX_PADDING;
+use super::util::ZeroCounter;
+use super::xxhio;
+
+/// How bad a lint issue is
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    /// Suspicious, but swdl will still accept the image
+    Warning,
+    /// swdl will reject the image
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// One problem found in an image
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Issue {
+    pub severity: Severity,
+    /// index of the part this issue is about, if any
+    pub part: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.part {
+            Some(index) => write!(f, "{}: part {}: {}", self.severity, index, self.message),
+            None => write!(f, "{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+/// Every issue found in an image, in the order they were found
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LintReport {
+    pub issues: Vec<Issue>,
+}
+
+impl LintReport {
+    /// Number of issues with the given severity
+    pub fn count(&self, severity: Severity) -> usize {
+        self.issues.iter().filter(|i| i.severity == severity).count()
+    }
+
+    /// Whether there are any errors, i.e. whether swdl would reject the image
+    pub fn has_errors(&self) -> bool {
+        self.count(Severity::Error) > 0
+    }
+
+    fn add<S: Into<String>>(&mut self, severity: Severity, part: Option<usize>, message: S) {
+        self.issues.push(Issue { severity, part, message: message.into() });
+    }
+
+    fn error<S: Into<String>>(&mut self, part: Option<usize>, message: S) {
+        self.add(Severity::Error, part, message)
+    }
+
+    fn warn<S: Into<String>>(&mut self, part: Option<usize>, message: S) {
+        self.add(Severity::Warning, part, message)
+    }
+}
+
+/// Warn if any of the bytes in buf aren't zero
+fn check_reserved(report: &mut LintReport, part: Option<usize>, buf: &[u8], what: &str) {
+    let nonzero = buf.iter().filter(|b| **b != 0).count();
+    if nonzero > 0 {
+        report.warn(part, format!("{} of the {} {} bytes aren't zero", nonzero, buf.len(), what));
+    }
+}
+
+/**
+ * Check a raw image header, like ImageHeader::from_bytes but without stopping at the first
+ * problem. Returns the index and header of every part header that could be parsed, or None
+ * if the header is too broken to find the parts.
+ */
+fn lint_header(buf: &[u8], report: &mut LintReport) -> Option<Vec<(usize, PartHeader)>> {
+    let magic = u64::from_le_bytes(buf[..8].try_into().unwrap());
+    if magic != NIMG_HDR_MAGIC {
+        report.error(None, format!("bad image magic 0x{:016x}", magic));
+        return None;
+    }
+    let version = buf[8];
+    let layout = match Layout::for_version(version) {
+        Some(layout) => layout,
+        None => {
+            report.error(None, format!("unsupported image version {}", version));
+            return None;
+        }
+    };
+    if buf.len() != layout.header_size {
+        report.error(None, "image is too short to hold a header");
+        return None;
+    }
+    let hdr_size = layout.header_size;
+
+    let mut num_parts = buf[9] as usize;
+    if num_parts > NIMG_MAX_PARTS {
+        report.error(
+            None,
+            format!("part count {} exceeds the maximum of {}", num_parts, NIMG_MAX_PARTS),
+        );
+        num_parts = NIMG_MAX_PARTS;
+    }
+
+    let digest_type = if layout.has_digests {
+        let dtype = match DigestType::try_from(buf[10]) {
+            Ok(dtype) => dtype,
+            Err(e) => {
+                // without the digest type, there's no way to parse the part headers
+                report.error(None, e.to_string());
+                return None;
+            }
+        };
+        let expected = &buf[(hdr_size - DIGEST_LEN)..];
+        let actual = digest::digest(dtype, &buf[..(hdr_size - DIGEST_LEN)]);
+        if expected != actual.bytes {
+            report.error(None, format!("header digest doesn't match, it should be {}", actual));
+        }
+        check_reserved(report, None, &buf[11..16], "reserved header");
+        Some(dtype)
+    } else {
+        let expected = u32::from_le_bytes(buf[(hdr_size - 4)..].try_into().unwrap());
+        let actual = xxhio::xxhash32(&buf[..(hdr_size - 4)]);
+        if expected != actual {
+            report.error(
+                None,
+                format!("header xxHash is 0x{:08x}, it should be 0x{:08x}", expected, actual),
+            );
+        }
+        check_reserved(report, None, &buf[10..16], "reserved header");
+        None
+    };
+
+    // swdl doesn't care about the name, but it's printed and used in filenames
+    let name = &buf[16..(16 + NIMG_NAME_LEN)];
+    let name_len = name.iter().position(|c| *c == b'\0').unwrap_or(name.len());
+    match std::str::from_utf8(&name[..name_len]) {
+        Ok(s) if s.chars().any(char::is_control) => {
+            report.warn(None, "image name contains control characters")
+        }
+        Ok(_) => (),
+        Err(_) => report.warn(None, "image name isn't valid UTF-8"),
+    }
+    check_reserved(report, None, &name[name_len..], "image name padding");
+
+    let parts_start = 16 + NIMG_NAME_LEN;
+    let mut parts = Vec::new();
+    for index in 0..NIMG_MAX_PARTS {
+        let start = parts_start + index * layout.part_header_size;
+        let phdr = &buf[start..(start + layout.part_header_size)];
+        if index >= num_parts {
+            check_reserved(report, None, phdr, &format!("unused part header {}", index));
+            continue;
+        }
+        match PartHeader::from_bytes(phdr, version, digest_type) {
+            Ok(part) => {
+                check_reserved(report, Some(index), &phdr[26..28], "reserved part header");
+                parts.push((index, part));
+            }
+            Err(e) => report.error(Some(index), format!("invalid part header: {}", e)),
+        }
+    }
+    let unused_start = parts_start + NIMG_MAX_PARTS * layout.part_header_size;
+    let unused_end = hdr_size - if layout.has_digests { DIGEST_LEN } else { 4 };
+    check_reserved(report, None, &buf[unused_start..unused_end], "reserved header");
+
+    Some(parts)
+}
+
+/// Structural checks of the part headers, which don't need any of the part data
+fn lint_parts(parts: &[(usize, PartHeader)], report: &mut LintReport) {
+    for (i, (index, part)) in parts.iter().enumerate() {
+        if part.size == 0 {
+            report.warn(Some(*index), "part is empty");
+        }
+        if let Some((other, _)) = parts[..i].iter().find(|(_, p)| p.ptype == part.ptype) {
+            report.warn(Some(*index), format!("part has the same type as part {}", other));
+        }
+        if i > 0 && part.offset < parts[i - 1].1.offset {
+            report.error(
+                Some(*index),
+                format!(
+                    "part offset {} is before part {}, swdl needs parts in order",
+                    part.offset,
+                    parts[i - 1].0
+                ),
+            );
+        }
+    }
+}
+
+/**
+ * Check the part data in a single pass through the image, in order of part offset. Stops
+ * early and returns Ok if the image is truncated, after reporting that.
+ */
+fn lint_data<R: Read>(
+    mut reader: R,
+    parts: &[(usize, PartHeader)],
+    report: &mut LintReport,
+) -> io::Result<()> {
+    let mut sorted = parts.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|(_, part)| part.offset);
+
+    // offset of the end of the last part read, and which part that was
+    let mut pos = 0u64;
+    let mut last = None;
+    for (index, part) in sorted.into_iter() {
+        let index = *index;
+        if part.offset < pos {
+            report.error(
+                Some(index),
+                format!("part overlaps part {}, its data wasn't checked", last.unwrap()),
+            );
+            continue;
+        }
+
+        let mut padding = ZeroCounter::default();
+        io::copy(&mut (&mut reader).take(part.offset - pos), &mut padding)?;
+        if padding.count != part.offset - pos {
+            report.error(Some(index), "image is truncated before the start of the part data");
+            return Ok(());
+        }
+        if padding.count > DEFAULT_MAX_PADDING {
+            report.error(
+                Some(index),
+                format!(
+                    "{} bytes of padding before the part is more than the default limit of {}",
+                    padding.count, DEFAULT_MAX_PADDING
+                ),
+            );
+        }
+        if padding.nonzero > 0 {
+            report.warn(
+                Some(index),
+                format!(
+                    "{} of the {} padding bytes before the part aren't zero",
+                    padding.nonzero, padding.count
+                ),
+            );
+        }
+
+        let mut hasher = PartHasher::for_part(part);
+        let size = io::copy(&mut (&mut reader).take(part.size), &mut hasher)?;
+        if size != part.size {
+            report.error(
+                Some(index),
+                format!("image is truncated, only {} of {} bytes of part data", size, part.size),
+            );
+            return Ok(());
+        }
+        if let Err(e) = hasher.verify(part) {
+            report.error(Some(index), e.to_string());
+        }
+        pos = part.offset + part.size;
+        last = Some(index);
+    }
+
+    // anything after the last part should only be padding to the usual alignment
+    let mut trailing = ZeroCounter::default();
+    io::copy(&mut reader, &mut trailing)?;
+    if trailing.count > part_padding(pos) || trailing.nonzero > 0 {
+        report.warn(None, format!("{} bytes of trailing data after the last part", trailing.count));
+    }
+    Ok(())
+}
+
+/**
+ * Read an entire image from reader and report every problem found in it. I/O errors are
+ * returned as errors, everything else, including a truncated image, is in the report.
+ */
+pub fn lint_image<R: Read>(mut reader: R) -> io::Result<LintReport> {
+    let mut report = LintReport::default();
+    let raw = match ImageHeader::read_raw(&mut reader) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            report.error(None, "image is too short to hold a header");
+            return Ok(report);
+        }
+        Err(e) => return Err(e),
+    };
+
+    if let Some(parts) = lint_header(&raw, &mut report) {
+        lint_parts(&parts, &mut report);
+        lint_data(reader, &parts, &mut report)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{ImageWriter, PartOptions};
+    use std::io::Cursor;
+
+    fn make_image() -> Vec<u8> {
+        let mut writer = ImageWriter::new(Cursor::new(Vec::new()), "lint test").unwrap();
+        for (data, ptype) in
+            [(&b"boot"[..], PartType::BootImg), (b"rootfs", PartType::Rootfs)].iter()
+        {
+            writer.add_part(*data, *ptype, CompMode::None, &PartOptions::default()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Rewrite the header of image with changes made by f
+    fn modify_header<F: FnOnce(&mut ImageHeader)>(image: &mut [u8], f: F) {
+        let mut header = ImageHeader::from_bytes(&image[..NIMG_V4_HDR_SIZE]).unwrap();
+        f(&mut header);
+        header.write_to(&mut image[..NIMG_V4_HDR_SIZE]).unwrap();
+    }
+
+    fn messages(image: &[u8]) -> Vec<String> {
+        lint_image(image).unwrap().issues.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn test_good_image() {
+        let report = lint_image(&make_image()[..]).unwrap();
+        assert_eq!(report, LintReport::default());
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn test_data_problems() {
+        let mut image = make_image();
+        // nonzero padding, a bad part, and trailing garbage are all reported
+        image[NIMG_V4_HDR_SIZE + 5] = 1;
+        image[NIMG_V4_HDR_SIZE + 17] ^= 1;
+        image.extend_from_slice(b"garbage!");
+        let report = lint_image(&image[..]).unwrap();
+        assert_eq!(report.count(Severity::Error), 1);
+        assert_eq!(report.count(Severity::Warning), 2);
+        let msgs = messages(&image);
+        assert_eq!(
+            msgs[0],
+            "warning: part 1: 1 of the 12 padding bytes before the part aren't zero"
+        );
+        assert!(msgs[1].starts_with("error: part 1: invalid part data hash"), "{}", msgs[1]);
+        assert_eq!(msgs[2], "warning: 18 bytes of trailing data after the last part");
+
+        image.truncate(NIMG_V4_HDR_SIZE + 18);
+        assert_eq!(
+            messages(&image)[1],
+            "error: part 1: image is truncated, only 2 of 6 bytes of part data"
+        );
+        assert_eq!(messages(&image[..100]), vec!["error: image is too short to hold a header"]);
+    }
+
+    #[test]
+    fn test_header_problems() {
+        let mut image = make_image();
+        modify_header(&mut image, |h| {
+            h.name = "bad\nname".to_string();
+            h.parts[1].offset = 2;
+            h.parts[1].ptype = PartType::BootImg;
+        });
+        let msgs = messages(&image);
+        assert_eq!(
+            msgs,
+            vec![
+                "warning: image name contains control characters",
+                "warning: part 1: part has the same type as part 0",
+                "error: part 1: part overlaps part 0, its data wasn't checked",
+                "warning: 28 bytes of trailing data after the last part",
+            ]
+        );
+
+        // a broken part header doesn't stop the other parts from being checked
+        let mut image = make_image();
+        let phdr = 16 + NIMG_NAME_LEN;
+        image[phdr] = 0;
+        image[NIMG_V4_HDR_SIZE + 16] ^= 1;
+        let msgs = messages(&image);
+        assert!(msgs[0].starts_with("error: header digest doesn't match"), "{}", msgs[0]);
+        assert!(msgs[1].starts_with("error: part 0: invalid part header: bad nImage part magic"));
+        // part 0's data looks like padding now
+        assert_eq!(
+            msgs[2],
+            "warning: part 1: 4 of the 16 padding bytes before the part aren't zero"
+        );
+        assert!(msgs[3].starts_with("error: part 1: invalid part data hash"), "{}", msgs[3]);
+        assert_eq!(msgs.len(), 4);
+    }
+}
@@ -6,84 +6,216 @@
  * SPDX-License-Identifier: GPL-3.0-or-later
  */
 
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::{self, Cursor, SeekFrom};
+use std::io::{self, BufReader, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, Context, Result};
 use clap::ArgMatches;
 use yall::log_macros::*;
 
-use nimage::format::*;
+use nimage::lint::{lint_image, Severity};
+use nimage::reader::{ExtraData, ImageReader};
+use nimage::sign::{check_signable, verify_header, TrustedKey, SIGNATURE_EXT};
 use nimage::util::*;
-use nimage::xxhio;
 
+use crate::extract::part_indexes;
+use crate::info::{print_header, OutputFormat};
 use crate::CmdResult;
 
-/// Read the last 4 bytes of buf as a u32le. Panics if buf isn't at least 4 bytes long
-fn last_u32(buf: &[u8]) -> u32 {
-    let mut reader = Cursor::new(buf);
-    reader.seek(SeekFrom::End(-4)).unwrap();
-    reader.read_u32_le().unwrap()
+/**
+ * Check the parts in indexes, or all parts if indexes is None, by reading through the image
+ * in order. Stops at the first bad part.
+ */
+fn check_sequential(image: &mut ImageReader<Input>, indexes: Option<&[usize]>) -> Result<()> {
+    let last = match indexes {
+        Some(indexes) => indexes.iter().max().cloned().unwrap_or(0),
+        None => usize::MAX,
+    };
+    while let Some(mut part) = image.next_part()? {
+        let i = part.index();
+        if indexes.map_or(true, |indexes| indexes.contains(&i)) {
+            io::copy(&mut part, &mut io::sink())?;
+        } else {
+            part.skip()?;
+        }
+        if i == last {
+            break;
+        }
+    }
+    Ok(())
 }
 
-/// Read exactly count bytes from input and return the xxHash32.
-fn read_exact_xxh<R: Read>(input: &mut R, count: u64) -> io::Result<u32> {
-    let mut writer = xxhio::Writer::new(io::sink());
-    let read = io::copy(&mut input.take(count), &mut writer)?;
-    if read == count {
-        Ok(writer.hash())
-    } else {
-        Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            format!("read only {}/{} bytes", read, count),
-        ))
+/**
+ * Check one part of the image file at path, using a new file handle so that parts can be
+ * checked in parallel.
+ */
+fn check_one_part(path: &str, raw_header: &[u8], index: usize) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(raw_header.len() as u64))?;
+    let mut image = ImageReader::with_raw_header(BufReader::new(file), raw_header.to_vec())?;
+    io::copy(&mut image.part(index)?, &mut io::sink())?;
+    Ok(())
+}
+
+/**
+ * Check the parts in indexes of the image file at path with a pool of jobs threads.
+ * Every part is checked, and every failure is reported, rather than stopping at the first.
+ */
+fn check_parallel(path: &str, raw_header: &[u8], indexes: &[usize], jobs: usize) -> Result<()> {
+    let path = Arc::new(path.to_string());
+    let raw_header = Arc::new(raw_header.to_vec());
+    let indexes = Arc::new(indexes.to_vec());
+    // index into indexes of the next part that needs a thread to check it
+    let next = Arc::new(AtomicUsize::new(0));
+
+    let jobs = jobs.max(1).min(indexes.len());
+    debug!("checking {} parts with {} threads", indexes.len(), jobs);
+    let threads = (0..jobs)
+        .map(|_| {
+            let (path, raw_header, indexes, next) =
+                (path.clone(), raw_header.clone(), indexes.clone(), next.clone());
+            thread::spawn(move || {
+                let mut results = Vec::new();
+                while let Some(&index) = indexes.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    results.push((index, check_one_part(&path, &raw_header, index)));
+                }
+                results
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = Vec::new();
+    for t in threads {
+        results.extend(t.join().map_err(|_| anyhow!("part check thread panicked"))?);
+    }
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut failed = 0;
+    for (index, result) in results.iter() {
+        match result {
+            Ok(()) => debug!("part {} OK", index),
+            Err(e) => {
+                // errors from ImageReader already say which part is bad
+                error!("{}", e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} of {} parts failed the check", failed, results.len()));
+    }
+    Ok(())
+}
+
+/**
+ * Fail if there's anything other than zeros outside of the parts. That doesn't stop the image
+ * from being used, but it's often a sign of a bad upload or two files concatenated together.
+ */
+fn check_extra_data(extra: ExtraData) -> Result<()> {
+    if extra.nonzero_padding > 0 {
+        error!("{} bytes of padding between parts aren't zero", extra.nonzero_padding);
     }
+    if extra.trailing > 0 {
+        error!("found {} bytes of unexpected data after the end of the image", extra.trailing);
+    }
+    if extra != ExtraData::default() {
+        return Err(anyhow!("image has unexpected data outside of its parts"));
+    }
+    Ok(())
+}
+
+/**
+ * Lint mode: report every problem with the image rather than stopping at the first one.
+ * Fails only if there are errors, warnings are just printed.
+ */
+fn lint(input: Input) -> CmdResult {
+    let report = lint_image(input)?;
+    for issue in report.issues.iter() {
+        let msg = match issue.part {
+            Some(index) => format!("part {}: {}", index, issue.message),
+            None => issue.message.clone(),
+        };
+        match issue.severity {
+            Severity::Error => error!("{}", msg),
+            Severity::Warning => warn!("{}", msg),
+        }
+    }
+
+    let errors = report.count(Severity::Error);
+    let warnings = report.count(Severity::Warning);
+    if errors > 0 {
+        return Err(anyhow!("Image lint found {} errors and {} warnings", errors, warnings));
+    }
+    info!("Image lint SUCCESS with {} warnings", warnings);
+    Ok(())
 }
 
-#[allow(clippy::comparison_chain)] // suppress lint on the "if part.offset < current_offset"
 pub fn cmd_check(args: &ArgMatches) -> CmdResult {
-    let mut input = Input::open_file_or_stdin(args.value_of("FILE").unwrap_or("-"))?;
+    let format = OutputFormat::from_args(args)?;
+    let input = Input::open_file_or_stdin(args.value_of("FILE").unwrap_or("-"))?;
     info!("{}:", input);
-    let mut header_bytes = [0u8; NIMG_HDR_SIZE];
-    input.read_exact(&mut header_bytes)?;
-    let header = ImageHeader::from_bytes(&header_bytes)?;
-
-    // header doesn't store its xxh, get it from the original buffer
-    let xxh = last_u32(&header_bytes);
-    let mut header_str = Vec::<u8>::new();
-    header.print_to(&mut header_str, Some(xxh))?;
-    info!("{}", std::str::from_utf8(&header_str).unwrap());
-
-    // validate all the parts' data
-    let mut current_offset = 0u64;
-    for (i, part) in header.parts.iter().enumerate() {
-        // handle padding before this part
-        if part.offset < current_offset {
-            return Err(anyhow!("Part {} offset {} is out of order", i, part.offset));
-        } else if part.offset > current_offset {
-            let pad_bytes = part.offset - current_offset;
-            let mut padding = vec![0u8; pad_bytes as usize];
-            input
-                .read_exact(&mut padding)
-                .with_context(|| format!("failed to read padding before part {}", i))?;
-            current_offset += pad_bytes;
-        }
+    if args.is_present("lint") {
+        return lint(input);
+    }
+    let mut image = ImageReader::new(input).context("failed to read image header")?;
+    if let Some(max) = args.value_of("max_padding") {
+        image.set_max_padding(max.parse().map_err(|_| anyhow!("invalid max padding '{}'", max))?);
+    }
+    let header = image.header().clone();
+    let checksum = header.checksum(image.raw_header());
+    if format == OutputFormat::Text {
+        print_header(&header, &checksum, format)?;
+    }
 
-        // wrap the input to only read part.size bytes, then wrap that in a hash reader
-        let actual_xxh = read_exact_xxh(&mut input, part.size)
-            .with_context(|| format!("failed to read data for part {}", i))?;
-        if actual_xxh != part.xxh {
-            return Err(anyhow!(
-                "Part {} hash is invalid: expected 0x{:08x} actual 0x{:08x}",
-                i,
-                part.xxh,
-                actual_xxh
-            ));
-        }
+    // check the signature before any of the data, if we were given keys to check it with
+    if let Some(key_paths) = args.values_of("verify_key") {
+        let keys = key_paths.map(|p| TrustedKey::load(Path::new(p))).collect::<Result<Vec<_>>>()?;
+        let input = image.get_ref();
+        let sig_path = match args.value_of("signature") {
+            Some(path) => path.to_string(),
+            None if input.is_file() => format!("{}{}", input, SIGNATURE_EXT),
+            None => return Err(anyhow!("a signature file is required when reading stdin")),
+        };
+        let signature = fs::read_to_string(&sig_path)
+            .with_context(|| format!("failed to read signature '{}'", sig_path))?;
+        let key = verify_header(image.raw_header(), &signature, &keys)?;
+        check_signable(&header)?;
+        info!("Signature OK, signed by {}", key.path.display());
+    }
 
-        current_offset += part.size;
+    // validate the parts' data. ImageReader checks the size, xxHash, and digest (if there
+    // is one) of each part as it's read.
+    let indexes = part_indexes(args, header.parts.len())?;
+    if image.get_ref().is_file() {
+        let jobs = match args.value_of("jobs") {
+            Some(j) => j.parse::<usize>().map_err(|_| anyhow!("invalid job count '{}'", j))?,
+            None => num_cpus::get(),
+        };
+        let all = (0..header.parts.len()).collect::<Vec<_>>();
+        let path = image.get_ref().to_string();
+        check_parallel(&path, image.raw_header(), indexes.as_ref().unwrap_or(&all), jobs)?;
+        if indexes.is_none() {
+            check_extra_data(image.scan_extra_data()?)?;
+        }
+    } else {
+        check_sequential(&mut image, indexes.as_deref())?;
+        if indexes.is_none() {
+            check_extra_data(image.read_to_end()?)?;
+        }
+    }
+    match indexes {
+        Some(indexes) => info!("Image check SUCCESS (parts {:?} only)", indexes),
+        None => info!("Image check SUCCESS"),
     }
 
-    info!("Image check SUCCESS");
+    // machine-readable output is only printed for images which pass the check
+    if format != OutputFormat::Text {
+        print_header(&header, &checksum, format)?;
+    }
     Ok(())
 }
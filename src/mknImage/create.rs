@@ -9,33 +9,33 @@
 use std::convert::TryFrom;
 use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::{self, BufReader, SeekFrom};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
 use clap::ArgMatches;
 use yall::log_macros::*;
-use zstd::stream::read::Encoder as ZstdReadEncoder;
 
+use nimage::decode;
+use nimage::digest::DigestType;
 use nimage::format::*;
-use nimage::util::WriteHelper;
-use nimage::xxhio;
+use nimage::writer::{ImageWriter, PartOptions, StreamWriter};
 
 use crate::CmdResult;
 
+/// Output file which is deleted when dropped, unless finish() was called
 #[derive(Debug)]
-struct Output {
+pub struct Output {
     path: PathBuf,
     file: File,
     finished: bool,
-    pub count: u64,
 }
 
 impl Output {
-    pub fn new(filename: &str) -> io::Result<Self> {
-        let path = PathBuf::from(filename);
+    pub fn new<P: AsRef<Path>>(filename: P) -> io::Result<Self> {
+        let path = filename.as_ref().to_path_buf();
         let file = File::create(&path)?;
-        Ok(Output { path, file, finished: false, count: 0 })
+        Ok(Output { path, file, finished: false })
     }
 
     pub fn finish(&mut self) {
@@ -45,11 +45,7 @@ impl Output {
 
 impl Write for Output {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let ret = self.file.write(buf);
-        if let Ok(count) = ret {
-            self.count += count as u64;
-        }
-        ret
+        self.file.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -140,54 +136,98 @@ fn parse_input(arg: &str) -> Result<PartInput> {
     Ok(PartInput { filename, ptype, comp, auto_comp })
 }
 
-fn add_part(output: &mut Output, header: &mut ImageHeader, pinput: &PartInput) -> CmdResult {
-    const ALIGN: u64 = 16;
-    let infile = File::open(pinput.filename)
+/**
+ * Open a part's input file. For libarchive parts, also check that swdl will be able to
+ * decompress it.
+ */
+fn open_part(pinput: &PartInput) -> Result<BufReader<File>> {
+    let mut infile = File::open(pinput.filename)
+        .map(BufReader::new)
         .with_context(|| format!("Unable to open '{}' for reading", pinput.filename))?;
 
-    let mut reader = match pinput.auto_comp {
-        Some(level) => {
-            debug!("compressing part '{}' with zstd level {}", pinput.filename, level);
-            let mut zenc = ZstdReadEncoder::new(BufReader::new(infile), level)?;
-            // try to enable multithreading, but ignore errors if it doesn't work
-            let _ = zenc.multithread(num_cpus::get() as u32);
-            xxhio::Reader::new(zenc)
+    // libarchive parts are opaque, but make sure swdl will actually be able to decompress it
+    if pinput.comp == CompMode::LibArchive {
+        match decode::detect(infile.fill_buf()?) {
+            Some(format) => debug!("part '{}' is compressed with {}", pinput.filename, format),
+            None => {
+                return Err(anyhow!(
+                    "'{}' is not compressed with a supported libarchive format",
+                    pinput.filename
+                ))
+            }
         }
-        None => xxhio::Reader::new(BufReader::new(infile)),
-    };
+    }
 
     debug!("Opened part input file '{}'", pinput.filename);
-    let offset = output.count;
-    debug!("start writing output at offset {}", offset);
-    io::copy(&mut reader, output)?;
+    Ok(infile)
+}
 
-    let size = reader.total_len();
-    let xxh = reader.hash();
-    let pheader = PartHeader { size, offset, ptype: pinput.ptype, comp: pinput.comp, xxh };
-    debug!("Created PartHeader {:?}", pheader);
+fn part_options(pinput: &PartInput) -> PartOptions {
+    if let Some(level) = pinput.auto_comp {
+        debug!("compressing part '{}' with zstd level {}", pinput.filename, level);
+    }
+    PartOptions { zstd_level: pinput.auto_comp, zstd_threads: num_cpus::get() as u32 }
+}
 
+fn print_part(index: usize, pinput: &PartInput, pheader: &PartHeader) {
+    debug!("Created PartHeader {:?}", pheader);
     let mut pheader_str = Vec::<u8>::new();
     pheader.print_to(&mut pheader_str, 2).unwrap();
     // note: the number of spaces here should match PartHeader::print_to() for alignment
     info!(
         "Part {}\n  file:        {}\n{}",
-        header.parts.len(),
+        index,
         pinput.filename,
         std::str::from_utf8(&pheader_str).unwrap()
     );
+}
+
+fn add_part<W: Write + Seek>(writer: &mut ImageWriter<W>, pinput: &PartInput) -> CmdResult {
+    let infile = open_part(pinput)?;
+    let index = writer.header().parts.len();
+    let pheader = writer
+        .add_part(infile, pinput.ptype, pinput.comp, &part_options(pinput))
+        .with_context(|| format!("failed to add part '{}'", pinput.filename))?;
+    print_part(index, pinput, pheader);
+    Ok(())
+}
 
-    let padding = (ALIGN - (size % ALIGN)) % ALIGN;
-    if padding > 0 {
-        debug!("Writing {} bytes of padding", padding);
-        output.write_zeros(padding as usize)?;
+/**
+ * Write an image to a non-seekable output. Every input file is read twice, first to calculate
+ * the header and then to write the data, so no temporary files are needed.
+ */
+fn create_stream<W: Write>(output: W, header: ImageHeader, input_parts: &[PartInput]) -> CmdResult {
+    let mut writer = StreamWriter::with_header(output, header)?;
+    for (index, pinput) in input_parts.iter().enumerate() {
+        let infile = open_part(pinput)?;
+        let pheader = writer
+            .plan_part(infile, pinput.ptype, pinput.comp, &part_options(pinput))
+            .with_context(|| format!("failed to add part '{}'", pinput.filename))?;
+        print_part(index, pinput, pheader);
     }
 
-    header.parts.push(pheader);
+    debug!("Header is complete, writing image data");
+    for pinput in input_parts.iter() {
+        let infile = open_part(pinput)?;
+        writer
+            .write_part(infile, &part_options(pinput))
+            .with_context(|| format!("failed to write part '{}'", pinput.filename))?;
+    }
+    writer.finish().context("Failed to write image")?;
     Ok(())
 }
 
 pub fn cmd_create(args: &ArgMatches) -> CmdResult {
     let image_name = args.value_of("name").unwrap_or("");
+    let digest_type = DigestType::try_from(args.value_of("digest").unwrap_or("sha256"))
+        .map_err(|_| anyhow!("invalid digest type"))?;
+    let version = match args.value_of("format_version") {
+        Some(v) => match v.parse::<u8>() {
+            Ok(version) if Layout::for_version(version).is_some() => version,
+            _ => return Err(anyhow!("unsupported format version '{}'", v)),
+        },
+        None => NIMG_CURRENT_VERSION,
+    };
     let output_path = args.value_of("output").unwrap();
 
     let mut input_parts = Vec::<PartInput>::new();
@@ -197,25 +237,54 @@ pub fn cmd_create(args: &ArgMatches) -> CmdResult {
         input_parts.push(part);
     }
 
-    info!("Creating image {}", output_path);
+    let to_stdout = output_path == "-";
+    if to_stdout {
+        // safe because isatty has no side effects
+        if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+            return Err(anyhow!("refusing to write an image to a terminal"));
+        }
+        info!("Creating image on stdout");
+    } else {
+        info!("Creating image {}", output_path);
+    }
     info!("Image name is '{}'", image_name);
 
+    let mut header = ImageHeader::new(image_name);
+    header.version = version;
+    if header.layout().has_digests {
+        header.digest_type = Some(digest_type);
+    } else {
+        // don't waste time calculating digests that won't be written
+        if args.occurrences_of("digest") > 0 {
+            warn!("ignoring --digest, version {} images don't have digests", version);
+        }
+        header.digest_type = None;
+    }
+    if version != NIMG_CURRENT_VERSION {
+        info!("Writing nImage format version {}", version);
+    }
+    if header.digest_type.is_none() {
+        warn!(
+            "version {} images have no digests, mknImage sign will refuse to sign this image",
+            version
+        );
+    }
+
+    if to_stdout {
+        let stdout = io::stdout();
+        return create_stream(BufWriter::new(stdout.lock()), header, &input_parts);
+    }
+
     // input is parsed, open the output file
     let mut output = Output::new(&output_path)
         .with_context(|| format!("unable to open '{}' for writing", output_path))?;
-
-    // write header placeholder, then reset the write count to calculate correct offsets
-    output.write_zeros(NIMG_HDR_SIZE)?;
-    output.count = 0;
-
-    let mut header = ImageHeader::new(image_name);
+    let mut writer =
+        ImageWriter::with_header(&mut output, header).context("Failed to write image header")?;
     for part in input_parts.iter() {
-        add_part(&mut output, &mut header, part)?;
+        add_part(&mut writer, part)?;
     }
-
     // seek back to the beginning and write the real header
-    output.seek(SeekFrom::Start(0)).context("Failed to seek output file")?;
-    header.write_to(&mut output).context("Failed to write image header")?;
+    writer.finish().context("Failed to write image header")?;
 
     // success, don't delete the output file when we return
     output.finish();
@@ -0,0 +1,156 @@
+/*!
+ * mknImage: a tool to work with files in the nImage format.
+ * handler for the extract subcommand.
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ */
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use yall::log_macros::*;
+
+use nimage::decode;
+use nimage::format::*;
+use nimage::reader::{ImageReader, PartReader};
+use nimage::util::*;
+
+use crate::create::Output;
+use crate::CmdResult;
+
+/**
+ * Pick the output filename for a part, e.g. "1-rootfs.img" or "0-boot_tar.tar.zst".
+ * head is the start of the part data, used to find the extension of libarchive parts.
+ */
+fn output_name(index: usize, part: &PartHeader, decompress: bool, head: &[u8]) -> String {
+    let ext = match part.ptype {
+        PartType::BootTar => "tar",
+        _ => "img",
+    };
+    let comp_ext = match (decompress, part.comp) {
+        (true, _) | (_, CompMode::None) => None,
+        (false, CompMode::Zstd) => Some("zst"),
+        (false, CompMode::LibArchive) => Some(decode::detect(head).map_or("bin", |f| f.ext)),
+    };
+    match comp_ext {
+        Some(comp_ext) => format!("{}-{}.{}.{}", index, part.ptype, ext, comp_ext),
+        None => format!("{}-{}.{}", index, part.ptype, ext),
+    }
+}
+
+/**
+ * Copy one part's data to a new file in outdir, decompressing it if requested.
+ * The raw part data is checked against the part's size and hashes, and the output file is
+ * deleted if that fails. Returns the path of the output file.
+ */
+fn extract_part<R: Read>(
+    mut part: PartReader<R>,
+    outdir: &Path,
+    decompress: bool,
+) -> Result<PathBuf> {
+    let index = part.index();
+    let header = part.header().clone();
+    let mut data = BufReader::new(&mut part);
+    let path = outdir.join(output_name(index, &header, decompress, data.fill_buf()?));
+    let mut output = Output::new(&path)
+        .with_context(|| format!("unable to open '{}' for writing", path.display()))?;
+
+    {
+        let comp = if decompress { header.comp } else { CompMode::None };
+        let mut decoder = decode::reader(comp, &mut data)
+            .with_context(|| format!("failed to decompress part {}", index))?;
+        io::copy(&mut decoder, &mut output)
+            .with_context(|| format!("failed to extract part {}", index))?;
+    }
+    // the decoder may stop before the end of the part data, finish() reads and hashes the
+    // rest of it too
+    part.finish()?;
+
+    output.flush()?;
+    output.finish();
+    Ok(path)
+}
+
+/**
+ * Parse the part index arguments, which must all be less than nparts. Returns None if there
+ * weren't any.
+ */
+pub fn part_indexes(args: &ArgMatches, nparts: usize) -> Result<Option<Vec<usize>>> {
+    let indexes = match args.values_of("part") {
+        Some(vals) => vals
+            .map(|v| v.parse::<usize>().map_err(|_| anyhow!("invalid part index '{}'", v)))
+            .collect::<Result<Vec<_>>>()?,
+        None => return Ok(None),
+    };
+    if let Some(i) = indexes.iter().find(|i| **i >= nparts) {
+        return Err(anyhow!("part {} doesn't exist, image has {} parts", i, nparts));
+    }
+    Ok(Some(indexes))
+}
+
+pub fn cmd_extract(args: &ArgMatches) -> CmdResult {
+    let input = Input::open_file_or_stdin(args.value_of("IMAGE").unwrap_or("-"))?;
+    let outdir = Path::new(args.value_of("outdir").unwrap_or("."));
+    let decompress = args.is_present("decompress");
+
+    let types = args
+        .values_of("type")
+        .map(|vals| {
+            vals.map(|v| PartType::try_from(v).map_err(|_| anyhow!("invalid part type '{}'", v)))
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?;
+
+    let mut image = ImageReader::new(input).context("failed to read image header")?;
+    let header = image.header().clone();
+    let indexes = part_indexes(args, header.parts.len())?;
+
+    let selected = |i: usize, part: &PartHeader| match (&indexes, &types) {
+        (Some(indexes), _) => indexes.contains(&i),
+        (_, Some(types)) => types.contains(&part.ptype),
+        (None, None) => true,
+    };
+    let last = match header.parts.iter().enumerate().rposition(|(i, p)| selected(i, p)) {
+        Some(x) => x,
+        None => {
+            warn!("no parts selected, nothing to extract");
+            return Ok(());
+        }
+    };
+
+    fs::create_dir_all(outdir)
+        .with_context(|| format!("failed to create directory '{}'", outdir.display()))?;
+
+    if image.get_ref().is_file() {
+        // seek straight to each selected part
+        for (i, part) in header.parts.iter().enumerate().filter(|(i, p)| selected(*i, p)) {
+            let path = extract_part(image.part(i)?, outdir, decompress)?;
+            info!("Extracted part {} ({}) to {}", i, part.ptype, path.display());
+        }
+        return Ok(());
+    }
+
+    // stop reading after the last selected part
+    while let Some(part) = image.next_part()? {
+        let i = part.index();
+        let ptype = part.header().ptype;
+        if selected(i, part.header()) {
+            let path = extract_part(part, outdir, decompress)?;
+            info!("Extracted part {} ({}) to {}", i, ptype, path.display());
+        } else {
+            debug!("skipping part {} ({})", i, ptype);
+            part.skip()?;
+        }
+        if i == last {
+            break;
+        }
+    }
+
+    Ok(())
+}
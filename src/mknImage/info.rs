@@ -0,0 +1,192 @@
+/*!
+ * mknImage: a tool to work with files in the nImage format.
+ * handler for the info subcommand, and header output formats shared with check.
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ */
+
+use std::io;
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use serde::Serialize;
+use yall::log_macros::*;
+
+use nimage::format::*;
+use nimage::util::*;
+
+use crate::CmdResult;
+
+/// Version of the JSON and shell output schemas. Bump this for any incompatible change.
+pub const INFO_SCHEMA_VERSION: u32 = 2;
+
+/// list of output format names for the --format option
+pub static OUTPUT_FORMAT_NAMES: [&str; 3] = ["text", "json", "shell"];
+
+/// How to print image header information
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// human-readable text, printed through the logger
+    Text,
+    /// a single JSON object on stdout
+    Json,
+    /// NIMG_* shell variable assignments on stdout, suitable for eval
+    Shell,
+}
+
+impl OutputFormat {
+    /**
+     * Get the output format from the "format" argument, defaulting to Text.
+     */
+    pub fn from_args(args: &ArgMatches) -> Result<Self> {
+        match args.value_of("format").unwrap_or("text") {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "shell" => Ok(Self::Shell),
+            x => Err(anyhow!("invalid output format '{}'", x)),
+        }
+    }
+}
+
+/// JSON representation of an ImageHeader
+#[derive(Serialize)]
+struct ImageInfo<'a> {
+    schema_version: u32,
+    name: &'a str,
+    version: u8,
+    /// expected size of the whole image file, in bytes
+    image_size: u64,
+    /// null for v4 and later, which have header_digest instead
+    header_xxh: Option<String>,
+    /// null for v3
+    header_digest: Option<String>,
+    parts: Vec<PartInfo>,
+}
+
+/// JSON representation of a PartHeader
+#[derive(Serialize)]
+struct PartInfo {
+    index: usize,
+    #[serde(rename = "type")]
+    ptype: String,
+    compression: String,
+    size: u64,
+    offset: u64,
+    xxh: String,
+    /// null for v3
+    digest: Option<String>,
+}
+
+/// Quote a string for a POSIX shell, using single quotes
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn print_json<W: Write>(
+    w: &mut W,
+    header: &ImageHeader,
+    checksum: &HeaderChecksum,
+) -> io::Result<()> {
+    let info = ImageInfo {
+        schema_version: INFO_SCHEMA_VERSION,
+        name: &header.name,
+        version: header.version,
+        image_size: header.image_size(),
+        header_xxh: match checksum {
+            HeaderChecksum::Xxh32(_) => Some(checksum.to_string()),
+            HeaderChecksum::Digest(_) => None,
+        },
+        header_digest: match checksum {
+            HeaderChecksum::Xxh32(_) => None,
+            HeaderChecksum::Digest(_) => Some(checksum.to_string()),
+        },
+        parts: header
+            .parts
+            .iter()
+            .enumerate()
+            .map(|(index, part)| PartInfo {
+                index,
+                ptype: part.ptype.to_string(),
+                compression: part.comp.to_string(),
+                size: part.size,
+                offset: part.offset,
+                xxh: format!("0x{:08x}", part.xxh),
+                digest: part.digest.map(|d| d.to_string()),
+            })
+            .collect(),
+    };
+    serde_json::to_writer_pretty(&mut *w, &info)?;
+    writeln!(w)
+}
+
+/// Variables which don't exist in this image version are set to an empty string
+fn print_shell<W: Write>(
+    w: &mut W,
+    header: &ImageHeader,
+    checksum: &HeaderChecksum,
+) -> io::Result<()> {
+    let (xxh, digest) = match checksum {
+        HeaderChecksum::Xxh32(_) => (checksum.to_string(), String::new()),
+        HeaderChecksum::Digest(_) => (String::new(), checksum.to_string()),
+    };
+    writeln!(w, "NIMG_SCHEMA_VERSION={}", INFO_SCHEMA_VERSION)?;
+    writeln!(w, "NIMG_NAME={}", shell_quote(&header.name))?;
+    writeln!(w, "NIMG_VERSION={}", header.version)?;
+    writeln!(w, "NIMG_IMAGE_SIZE={}", header.image_size())?;
+    writeln!(w, "NIMG_HEADER_XXH={}", xxh)?;
+    writeln!(w, "NIMG_HEADER_DIGEST={}", digest)?;
+    writeln!(w, "NIMG_PART_COUNT={}", header.parts.len())?;
+    for (i, part) in header.parts.iter().enumerate() {
+        writeln!(w, "NIMG_PART{}_TYPE={}", i, part.ptype)?;
+        writeln!(w, "NIMG_PART{}_COMPRESSION={}", i, part.comp)?;
+        writeln!(w, "NIMG_PART{}_SIZE={}", i, part.size)?;
+        writeln!(w, "NIMG_PART{}_OFFSET={}", i, part.offset)?;
+        writeln!(w, "NIMG_PART{}_XXH=0x{:08x}", i, part.xxh)?;
+        let digest = part.digest.map(|d| d.to_string()).unwrap_or_default();
+        writeln!(w, "NIMG_PART{}_DIGEST={}", i, digest)?;
+    }
+    Ok(())
+}
+
+/**
+ * Print image header information in the given format. Text goes through the logger,
+ * JSON and shell output goes to stdout so that it can be piped to other programs.
+ */
+pub fn print_header(
+    header: &ImageHeader,
+    checksum: &HeaderChecksum,
+    format: OutputFormat,
+) -> io::Result<()> {
+    let stdout = io::stdout();
+    match format {
+        OutputFormat::Text => {
+            let mut header_str = Vec::<u8>::new();
+            header.print_to(&mut header_str, Some(checksum))?;
+            info!("{}", std::str::from_utf8(&header_str).unwrap());
+            Ok(())
+        }
+        OutputFormat::Json => print_json(&mut stdout.lock(), header, checksum),
+        OutputFormat::Shell => print_shell(&mut stdout.lock(), header, checksum),
+    }
+}
+
+/**
+ * Read and parse an image header of any version from input. Returns the parsed header along
+ * with the raw header bytes, which are needed for the header's checksum and signature.
+ */
+pub fn read_header<R: Read>(input: &mut R) -> Result<(ImageHeader, Vec<u8>)> {
+    Ok(ImageHeader::read_from(input).context("failed to read image header")?)
+}
+
+pub fn cmd_info(args: &ArgMatches) -> CmdResult {
+    let format = OutputFormat::from_args(args)?;
+    let mut input = Input::open_file_or_stdin(args.value_of("FILE").unwrap_or("-"))?;
+    let (header, header_bytes) = read_header(&mut input)?;
+    if format == OutputFormat::Text {
+        info!("{}:", input);
+    }
+    print_header(&header, &header.checksum(&header_bytes), format)?;
+    Ok(())
+}
@@ -14,14 +14,24 @@
 
 mod check;
 mod create;
+mod extract;
 mod hash;
+mod info;
+mod sign;
 
 use std::cmp::Ordering;
 
 use clap::{crate_version, App, AppSettings, Arg, ArgMatches, ArgSettings, SubCommand};
 use yall::{log_macros::*, LevelFilter, Logger};
 
-use nimage::format::{COMP_MODE_NAMES, NIMG_MAX_PARTS, NIMG_NAME_LEN, PART_TYPE_NAMES};
+use info::OUTPUT_FORMAT_NAMES;
+use nimage::decode::LIBARCHIVE_FORMATS;
+use nimage::digest::DIGEST_TYPE_NAMES;
+use nimage::format::{
+    COMP_MODE_NAMES, NIMG_CURRENT_VERSION, NIMG_MAX_PARTS, NIMG_MIN_VERSION, NIMG_NAME_LEN,
+    PART_TYPE_NAMES,
+};
+use nimage::reader::DEFAULT_MAX_PADDING;
 
 // exports to command modules
 pub type CmdResult = anyhow::Result<()>;
@@ -31,7 +41,11 @@ fn get_handler(name: &str) -> CmdHandler {
     match name {
         "create" => create::cmd_create,
         "check" => check::cmd_check,
+        "extract" => extract::cmd_extract,
         "hash" => hash::cmd_hash,
+        "info" => info::cmd_info,
+        "genkey" => sign::cmd_genkey,
+        "sign" => sign::cmd_sign,
         _ => unreachable!("command handler not found"),
     }
 }
@@ -40,6 +54,18 @@ fn main() {
     // comma separated string listing all the valid part types. Skip the first "invalid" entry
     let part_types = PART_TYPE_NAMES.iter().skip(1).map(|x| x.1).collect::<Vec<&str>>().join(", ");
     let comp_modes = COMP_MODE_NAMES.iter().map(|x| x.1).collect::<Vec<&str>>().join(", ");
+    let libarchive_formats =
+        LIBARCHIVE_FORMATS.iter().map(|x| x.name).collect::<Vec<&str>>().join(", ");
+    let digest_types = DIGEST_TYPE_NAMES.iter().map(|x| x.1).collect::<Vec<&str>>();
+
+    // output format option shared by the check and info subcommands
+    let format_arg = Arg::with_name("format")
+        .short("f")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&OUTPUT_FORMAT_NAMES)
+        .default_value("text")
+        .help("Output format. json and shell are printed to stdout with a stable schema.");
 
     // To use format! anywhere in the help text, we have to create the app and call .get_matches()
     // all in one statement or else we'll get errors about passing references to temporary objects.
@@ -78,11 +104,31 @@ fn main() {
                         .takes_value(true)
                         .help(format!("Name to embed in the image (max {} chars)", NIMG_NAME_LEN).as_str())
                 )
+                .arg(
+                    Arg::with_name("digest")
+                        .short("d")
+                        .long("digest")
+                        .takes_value(true)
+                        .possible_values(&digest_types)
+                        .default_value("sha256")
+                        .help("Digest algorithm for the header and part data")
+                )
+                .arg(
+                    Arg::with_name("format_version")
+                        .long("format-version")
+                        .takes_value(true)
+                        .value_name("VERSION")
+                        .help(format!("nImage format version to write, for devices with an older swdl \
+                                       ({}-{}, default {}). Versions before {} have no digests.",
+                                      NIMG_MIN_VERSION, NIMG_CURRENT_VERSION, NIMG_CURRENT_VERSION,
+                                      NIMG_CURRENT_VERSION).as_str())
+                )
                 .arg(
                     Arg::with_name("output")
                         .value_name("IMAGE_FILE")
                         .required(true)
-                        .help("Output filename. Must be a regular seekable file, not a pipe")
+                        .help("Output filename, or '-' to write to stdout. When writing to stdout, \
+                               every part file is read twice.")
                 )
                 .arg(
                     Arg::with_name("parts")
@@ -99,17 +145,176 @@ fn main() {
                                      If the zstd compression mode is specified as 'zstd+' or 'zstd+N', \
                                      mknImage will assume the input file is uncompressed and compress it \
                                      with zstd level N (default 15), otherwise it's assumed the part is \
-                                     already compressed.",
-                                    part_types, comp_modes).as_str())
+                                     already compressed.\n\
+                                     Parts with the 'libarchive' compression mode must be \
+                                     compressed with one of: {}",
+                                    part_types, comp_modes, libarchive_formats).as_str())
         )
         .subcommand(
             SubCommand::with_name("check")
                 .about("Check an nImage file for errors and print header information")
+                .arg(format_arg.clone())
+                .arg(
+                    Arg::with_name("part")
+                        .short("p")
+                        .long("part")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("N")
+                        .help("Check only part number N (starting from 0). May be repeated.")
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Number of parts to check in parallel [default: number of CPUs]")
+                )
+                .arg(
+                    Arg::with_name("max_padding")
+                        .long("max-padding")
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .help(format!("Largest gap allowed between parts [default: {}]",
+                                      DEFAULT_MAX_PADDING).as_str())
+                )
+                .arg(
+                    Arg::with_name("lint")
+                        .short("l")
+                        .long("lint")
+                        .conflicts_with_all(&["part", "jobs", "verify_key"])
+                        .help("Report every problem with the image instead of stopping at the \
+                               first, including ones swdl doesn't care about. Fails only if \
+                               there are errors. --format is ignored.")
+                )
+                .arg(
+                    Arg::with_name("verify_key")
+                        .short("K")
+                        .long("verify-key")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("PUBKEY")
+                        .help("Verify the image signature against this public key. May be repeated.")
+                )
+                .arg(
+                    Arg::with_name("signature")
+                        .short("s")
+                        .long("signature")
+                        .takes_value(true)
+                        .requires("verify_key")
+                        .value_name("SIGFILE")
+                        .help("Signature file to verify [default: FILE.sig]")
+                )
                 .arg(
                     Arg::with_name("FILE")
                         .required(false)
                         .help("Input file. Read from stdin if FILE isn't present or is '-'")
                 )
+                .after_help("With --format json or shell, header information is printed to \
+                             stdout only if the image passes the check.\n\
+                             Parts of image files are checked in parallel, and every bad part is \
+                             reported. When reading stdin, parts are checked in order and the check \
+                             stops at the first bad part.")
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print nImage header information without checking the part data")
+                .arg(format_arg)
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(false)
+                        .help("Input file. Read from stdin if FILE isn't present or is '-'")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Extract parts from an nImage into files")
+                .arg(
+                    Arg::with_name("part")
+                        .short("p")
+                        .long("part")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("N")
+                        .help("Extract only part number N (starting from 0). May be repeated.")
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .short("t")
+                        .long("type")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .conflicts_with("part")
+                        .help("Extract only parts of type TYPE. May be repeated.")
+                )
+                .arg(
+                    Arg::with_name("outdir")
+                        .short("o")
+                        .long("output-dir")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Directory to write parts to, created if needed [default: .]")
+                )
+                .arg(
+                    Arg::with_name("decompress")
+                        .short("x")
+                        .long("decompress")
+                        .help("Decompress zstd and libarchive parts while extracting")
+                )
+                .arg(
+                    Arg::with_name("IMAGE")
+                        .required(false)
+                        .help("Input image. Read from stdin if IMAGE isn't present or is '-'")
+                )
+                .after_help(format!("Parts are written to files named after their index and type, \
+                                     e.g. '1-rootfs.img'. Each part's xxHash is verified while \
+                                     extracting, and the output file is deleted if it doesn't match.\n\
+                                     Valid part types are: {}",
+                                    part_types).as_str())
+        )
+        .subcommand(
+            SubCommand::with_name("genkey")
+                .about("Generate an Ed25519 key pair for signing images")
+                .arg(
+                    Arg::with_name("output")
+                        .value_name("KEYFILE")
+                        .required(true)
+                        .help("Secret key file to create. The public key is written to KEYFILE.pub")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Create a detached signature for an nImage")
+                .arg(
+                    Arg::with_name("key")
+                        .short("k")
+                        .long("key")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("KEYFILE")
+                        .help("Secret key file, as created by genkey")
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .value_name("SIGFILE")
+                        .help("Signature file to write [default: IMAGE.sig]")
+                )
+                .arg(
+                    Arg::with_name("IMAGE")
+                        .required(true)
+                        .help("Image file to sign")
+                )
+                .after_help("The signature covers the image header, which includes the size \
+                             and hash of every part. Install the public key in swdl's trusted \
+                             key directory to allow signed images to be flashed.")
         )
         .subcommand(
             SubCommand::with_name("hash")
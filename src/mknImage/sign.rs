@@ -0,0 +1,64 @@
+/*!
+ * mknImage: a tool to work with files in the nImage format.
+ * handlers for the genkey and sign subcommands.
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ */
+
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use yall::log_macros::*;
+
+use nimage::sign::*;
+use nimage::util::Input;
+
+use crate::info::read_header;
+use crate::CmdResult;
+
+/// Write a new file, failing if it already exists
+fn write_new_file(path: &str, mode: u32, data: &str) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut f| f.write_all(data.as_bytes()))
+        .with_context(|| format!("failed to create '{}'", path))
+}
+
+pub fn cmd_genkey(args: &ArgMatches) -> CmdResult {
+    let secret_path = args.value_of("output").unwrap();
+    let public_path = format!("{}{}", secret_path, PUBLIC_KEY_EXT);
+    let key = SigningKey::generate()?;
+
+    // the secret key is only readable by its owner
+    write_new_file(secret_path, 0o600, &format!("{}\n", key.secret_hex()))?;
+    write_new_file(&public_path, 0o644, &format!("{}\n", key.public_hex()))?;
+    info!("Wrote secret key to {} and public key to {}", secret_path, public_path);
+    Ok(())
+}
+
+pub fn cmd_sign(args: &ArgMatches) -> CmdResult {
+    let key = SigningKey::load(Path::new(args.value_of("key").unwrap()))?;
+    let image_path = args.value_of("IMAGE").unwrap();
+    let sig_path = match args.value_of("output") {
+        Some(path) => path.to_string(),
+        None => format!("{}{}", image_path, SIGNATURE_EXT),
+    };
+
+    let mut input = Input::open_file_or_stdin(image_path)?;
+    // sign the raw header bytes, rather than a re-serialized ImageHeader
+    let (header, header_bytes) = read_header(&mut input)?;
+    check_signable(&header)?;
+    let signature = key.sign_header(&header_bytes);
+    fs::write(&sig_path, format!("{}\n", signature))
+        .with_context(|| format!("failed to write '{}'", sig_path))?;
+    info!("Wrote signature for {} to {}", input, sig_path);
+    Ok(())
+}
@@ -0,0 +1,588 @@
+/*!
+ * Streaming reader for complete nImage files.
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * ImageReader reads the header, then hands out each part in order as a PartReader. Padding
+ * between parts is skipped, and each part's data is checked against its size, xxHash32, and
+ * digest as it's read, so tools which consume images don't have to do any of that themselves.
+ *
+ * When the underlying reader can seek, ImageReader::part() jumps straight to any part without
+ * reading the ones before it.
+ *
+ * Padding between parts and anything after the last part isn't covered by any hash, and
+ * doesn't matter for reading the parts. ImageReader keeps track of padding that isn't zero,
+ * and read_to_end() or scan_extra_data() report it along with any trailing data. Padding is
+ * read in small chunks and never buffered, but a corrupt or malicious header could still have
+ * a huge gap between parts, so gaps larger than the max padding are an error.
+ */
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::decode;
+use super::digest::PartHasher;
+use super::format::*;
+use super::util::ZeroCounter;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Default limit on the padding before a part, see ImageReader::set_max_padding()
+pub const DEFAULT_MAX_PADDING: u64 = 1 << 20;
+
+/**
+ * Data in an image outside of any part, see ImageReader::read_to_end(). None of it is covered
+ * by the header's hashes, so it should all be zero padding.
+ */
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExtraData {
+    /// number of padding bytes between and after parts which aren't zero
+    pub nonzero_padding: u64,
+    /// number of bytes after the end of the image and the usual padding after the last part
+    pub trailing: u64,
+}
+
+/// Verification state for the part that's currently being read
+struct PartState {
+    index: usize,
+    part: PartHeader,
+    hasher: PartHasher,
+    remaining: u64,
+    verified: bool,
+}
+
+/**
+ * Reads an nImage from any Read, one part at a time. Typical use:
+ *
+ * ```no_run
+ * # use std::io;
+ * # use nimage::reader::ImageReader;
+ * # fn main() -> io::Result<()> {
+ * let mut image = ImageReader::new(io::stdin())?;
+ * println!("image name is {}", image.header().name);
+ * while let Some(mut part) = image.next_part()? {
+ *     io::copy(&mut part.decompressed()?, &mut io::sink())?;
+ * }
+ * image.finish_part()
+ * # }
+ * ```
+ *
+ * The data returned by a PartReader is only known to be good once the whole part has been
+ * read. PartReader returns an error instead of EOF if the size or hashes don't match, and
+ * finish_part(), PartReader::finish(), or the next call to next_part() reads whatever the
+ * caller didn't and verifies it. A decompressor might stop before the end of the part data,
+ * so always call one of those after the last part.
+ */
+pub struct ImageReader<R> {
+    inner: R,
+    header: ImageHeader,
+    raw_header: Vec<u8>,
+    // number of bytes read after the end of the header
+    offset: u64,
+    // index of the next part returned by next_part()
+    next_index: usize,
+    current: Option<PartState>,
+    // number of nonzero padding bytes read so far
+    nonzero_padding: u64,
+    max_padding: u64,
+}
+
+impl<R: Read> ImageReader<R> {
+    /**
+     * Read and validate the image header from inner.
+     */
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let raw_header = ImageHeader::read_raw(&mut inner)?;
+        Self::with_raw_header(inner, raw_header)
+    }
+
+    /**
+     * Create an ImageReader for an image whose raw header was already read from inner with
+     * ImageHeader::read_raw(), e.g. to check its signature before parsing anything.
+     */
+    pub fn with_raw_header(inner: R, raw_header: Vec<u8>) -> io::Result<Self> {
+        let header = ImageHeader::from_bytes(&raw_header)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(ImageReader {
+            inner,
+            header,
+            raw_header,
+            offset: 0,
+            next_index: 0,
+            current: None,
+            nonzero_padding: 0,
+            max_padding: DEFAULT_MAX_PADDING,
+        })
+    }
+
+    /**
+     * Set the largest gap allowed between the end of one part and the start of the next,
+     * default DEFAULT_MAX_PADDING. Images written by ImageWriter have less than PART_ALIGN
+     * bytes of padding between parts.
+     */
+    pub fn set_max_padding(&mut self, max_padding: u64) {
+        self.max_padding = max_padding;
+    }
+
+    /// The parsed image header
+    pub fn header(&self) -> &ImageHeader {
+        &self.header
+    }
+
+    /// The raw image header bytes, e.g. to get the header checksum
+    pub fn raw_header(&self) -> &[u8] {
+        &self.raw_header
+    }
+
+    /// Access the underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Consume this ImageReader and return the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /**
+     * Verify the current part and move on to the next one, skipping any padding in between.
+     * Returns None after the last part.
+     */
+    pub fn next_part(&mut self) -> io::Result<Option<PartReader<'_, R>>> {
+        self.finish_part()?;
+
+        let index = self.next_index;
+        let part = match self.header.parts.get(index) {
+            Some(part) => part.clone(),
+            None => return Ok(None),
+        };
+        if part.offset < self.offset {
+            return Err(invalid_data(format!(
+                "part {} offset {} is out of order",
+                index, part.offset
+            )));
+        }
+
+        self.skip_padding(index, part.offset)?;
+
+        self.next_index += 1;
+        self.current = Some(PartState {
+            index,
+            hasher: PartHasher::for_part(&part),
+            remaining: part.size,
+            part,
+            verified: false,
+        });
+        Ok(Some(PartReader { image: self }))
+    }
+
+    /**
+     * Read whatever's left of the current part, if any, and verify it.
+     */
+    pub fn finish_part(&mut self) -> io::Result<()> {
+        if self.current.is_some() {
+            io::copy(&mut PartReader { image: self }, &mut io::sink())?;
+            self.current = None;
+        }
+        Ok(())
+    }
+
+    /**
+     * Read the rest of the image, skipping parts which haven't been read yet without verifying
+     * them, and then anything after the last part until EOF. Returns the non-zero padding and
+     * trailing data found, which doesn't include padding skipped by part().
+     */
+    pub fn read_to_end(&mut self) -> io::Result<ExtraData> {
+        while let Some(part) = self.next_part()? {
+            part.skip()?;
+        }
+        self.read_trailing()
+    }
+
+    /// Skip padding up to offset, which is the start of part index
+    fn skip_padding(&mut self, index: usize, offset: u64) -> io::Result<()> {
+        let pad_bytes = offset - self.offset;
+        if pad_bytes > self.max_padding {
+            return Err(invalid_data(format!(
+                "part {} has {} bytes of padding before it, more than the limit of {}",
+                index, pad_bytes, self.max_padding
+            )));
+        }
+        let mut padding = ZeroCounter::default();
+        io::copy(&mut (&mut self.inner).take(pad_bytes), &mut padding)?;
+        self.offset += padding.count;
+        self.nonzero_padding += padding.nonzero;
+        if padding.count != pad_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("failed to read padding before part {}", index),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read from the end of the last part to EOF
+    fn read_trailing(&mut self) -> io::Result<ExtraData> {
+        // only the normal alignment padding after the last part is counted as padding
+        let mut padding = ZeroCounter::default();
+        io::copy(&mut (&mut self.inner).take(part_padding(self.offset)), &mut padding)?;
+        let mut trailing = ZeroCounter::default();
+        io::copy(&mut self.inner, &mut trailing)?;
+        self.offset += padding.count + trailing.count;
+        self.nonzero_padding += padding.nonzero;
+        Ok(ExtraData { nonzero_padding: self.nonzero_padding, trailing: trailing.count })
+    }
+
+    fn read_part(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let state = self.current.as_mut().expect("no current part");
+        if state.remaining == 0 {
+            if !state.verified {
+                state
+                    .hasher
+                    .verify(&state.part)
+                    .map_err(|e| invalid_data(format!("part {} is invalid: {}", state.index, e)))?;
+                state.verified = true;
+            }
+            return Ok(0);
+        }
+
+        // careful not to truncate remaining on 32-bit systems
+        let max = buf.len().min(state.remaining.min(usize::MAX as u64) as usize);
+        let count = self.inner.read(&mut buf[..max])?;
+        if count == 0 && max != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "part {} is truncated: read only {}/{} bytes",
+                    state.index,
+                    state.hasher.total_len(),
+                    state.part.size
+                ),
+            ));
+        }
+        state.hasher.update(&buf[..count]);
+        state.remaining -= count as u64;
+        self.offset += count as u64;
+        Ok(count)
+    }
+}
+
+impl ImageReader<BufReader<File>> {
+    /**
+     * Open an image file and read its header. The returned ImageReader supports random
+     * access with part().
+     */
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> ImageReader<R> {
+    /**
+     * Seek directly to the start of part index and return a reader for it. Whatever's left of
+     * the current part isn't read or verified. After this part, next_part() continues with
+     * the part after it.
+     */
+    pub fn part(&mut self, index: usize) -> io::Result<PartReader<'_, R>> {
+        let part = match self.header.parts.get(index) {
+            Some(part) => part.clone(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "part {} doesn't exist, image has {} parts",
+                        index,
+                        self.header.parts.len()
+                    ),
+                ))
+            }
+        };
+
+        self.seek_data(part.offset)?;
+        self.next_index = index + 1;
+        self.current = Some(PartState {
+            index,
+            hasher: PartHasher::for_part(&part),
+            remaining: part.size,
+            part,
+            verified: false,
+        });
+        Ok(PartReader { image: self })
+    }
+
+    /**
+     * Check the padding between parts and anything after the last part, seeking over the
+     * part data without reading it. Returns the non-zero padding and trailing data found.
+     * Afterwards, the reader is at the end of the image.
+     */
+    pub fn scan_extra_data(&mut self) -> io::Result<ExtraData> {
+        self.current = None;
+        self.nonzero_padding = 0;
+        let mut end = 0;
+        for (index, part) in self.header.parts.clone().iter().enumerate() {
+            if part.offset < end {
+                return Err(invalid_data(format!(
+                    "part {} offset {} is out of order",
+                    index, part.offset
+                )));
+            }
+            // the header can't be trusted not to overflow
+            let part_end = part.offset.checked_add(part.size).ok_or_else(|| {
+                invalid_data(format!(
+                    "part {} offset {} and size {} are too large",
+                    index, part.offset, part.size
+                ))
+            })?;
+            self.seek_data(end)?;
+            self.skip_padding(index, part.offset)?;
+            end = part_end;
+        }
+        self.seek_data(end)?;
+        self.next_index = self.header.parts.len();
+        self.read_trailing()
+    }
+
+    /// Seek to offset bytes after the end of the header
+    fn seek_data(&mut self, offset: u64) -> io::Result<()> {
+        // The reader is always self.offset bytes past the end of the header, use that to find
+        // where the part data starts.
+        let data_start = self.inner.seek(SeekFrom::Current(0))? - self.offset;
+        let pos = data_start.checked_add(offset).ok_or_else(|| {
+            invalid_data(format!("offset {} is past the end of any file", offset))
+        })?;
+        self.inner.seek(SeekFrom::Start(pos))?;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+/**
+ * Reader for the raw data of one part, see ImageReader::next_part().
+ */
+pub struct PartReader<'a, R> {
+    image: &'a mut ImageReader<R>,
+}
+
+impl<'a, R: Read> PartReader<'a, R> {
+    /// Index of this part in the image
+    pub fn index(&self) -> usize {
+        self.image.current.as_ref().unwrap().index
+    }
+
+    /// Header of this part
+    pub fn header(&self) -> &PartHeader {
+        &self.image.current.as_ref().unwrap().part
+    }
+
+    /// Number of bytes of raw part data read so far
+    pub fn position(&self) -> u64 {
+        self.image.current.as_ref().unwrap().hasher.total_len()
+    }
+
+    /**
+     * Get a reader for the decompressed part data, according to the part's compression mode.
+     * Call finish() after the decompressor is done to verify the part.
+     */
+    pub fn decompressed(&mut self) -> io::Result<Box<dyn Read + '_>> {
+        let comp = self.header().comp;
+        decode::reader(comp, self)
+    }
+
+    /**
+     * Read the rest of this part and verify it.
+     */
+    pub fn finish(self) -> io::Result<()> {
+        self.image.finish_part()
+    }
+
+    /**
+     * Read the rest of this part without verifying it, for callers which don't care about
+     * this part's data.
+     */
+    pub fn skip(self) -> io::Result<()> {
+        self.image.current.as_mut().unwrap().verified = true;
+        self.image.finish_part()
+    }
+}
+
+impl<'a, R: Read> Read for PartReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.image.read_part(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::{self, DigestType};
+    use crate::xxhio::xxhash32;
+    use std::io::Cursor;
+
+    /// Build an image from (offset, data) pairs, with zero padding between parts
+    fn make_image(parts: &[(u64, &[u8])]) -> (ImageHeader, Vec<u8>) {
+        let mut header = ImageHeader::new("test");
+        let mut data = Vec::new();
+        for (offset, part) in parts.iter() {
+            data.resize(*offset as usize, 0);
+            data.extend_from_slice(part);
+            header.parts.push(PartHeader {
+                size: part.len() as u64,
+                offset: *offset,
+                ptype: PartType::Rootfs,
+                comp: CompMode::None,
+                xxh: xxhash32(part),
+                digest: Some(digest::digest(DigestType::Sha256, part)),
+            });
+        }
+        let mut image = Vec::new();
+        header.write_to(&mut image).unwrap();
+        image.extend_from_slice(&data);
+        (header, image)
+    }
+
+    fn read_all(image: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut reader = ImageReader::new(Cursor::new(image))?;
+        let mut parts = Vec::new();
+        while let Some(mut part) = reader.next_part()? {
+            let mut data = Vec::new();
+            part.read_to_end(&mut data)?;
+            parts.push(data);
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn test_read_parts() {
+        let (header, image) = make_image(&[(0, b"first part"), (16, b"second"), (40, b"")]);
+        assert_eq!(read_all(&image).unwrap(), vec![&b"first part"[..], b"second", b""]);
+
+        // a part can be left partially read, the rest is verified by finish_part
+        let mut reader = ImageReader::new(Cursor::new(&image)).unwrap();
+        assert_eq!(reader.header(), &header);
+        let mut part = reader.next_part().unwrap().unwrap();
+        let mut buf = [0u8; 5];
+        part.read_exact(&mut buf).unwrap();
+        assert_eq!(part.position(), 5);
+        let mut part = reader.next_part().unwrap().unwrap();
+        assert_eq!(part.index(), 1);
+        part.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"secon");
+        part.finish().unwrap();
+    }
+
+    #[test]
+    fn test_skip_part() {
+        let (_, mut image) = make_image(&[(0, b"first part"), (16, b"second")]);
+        image[NIMG_V4_HDR_SIZE] ^= 1;
+        let mut reader = ImageReader::new(Cursor::new(&image)).unwrap();
+        reader.next_part().unwrap().unwrap().skip().unwrap();
+        let mut data = Vec::new();
+        reader.next_part().unwrap().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"second");
+        assert!(reader.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_random_access() {
+        let (_, mut image) = make_image(&[(0, b"first part"), (16, b"second"), (32, b"third")]);
+        // part 1 is never read, so corrupting it doesn't matter
+        image[NIMG_V4_HDR_SIZE + 16] ^= 1;
+        // the image doesn't have to be at the start of the file
+        image.splice(0..0, b"junk".iter().cloned());
+        let mut cursor = Cursor::new(&image);
+        cursor.set_position(4);
+        let mut reader = ImageReader::new(cursor).unwrap();
+
+        let mut data = Vec::new();
+        reader.part(2).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"third");
+        assert!(reader.next_part().unwrap().is_none());
+
+        // go back to the first part, and leave it partially read
+        let mut buf = [0u8; 5];
+        reader.part(0).unwrap().read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"first");
+        data.clear();
+        reader.part(2).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"third");
+
+        let err = reader.part(3).err().unwrap();
+        assert_eq!(err.to_string(), "part 3 doesn't exist, image has 3 parts");
+    }
+
+    #[test]
+    fn test_extra_data() {
+        let (_, mut image) = make_image(&[(0, b"first part"), (16, b"second")]);
+        let extra = |image: &[u8]| {
+            let sequential = ImageReader::new(image).unwrap().read_to_end().unwrap();
+            let seek = ImageReader::new(Cursor::new(image)).unwrap().scan_extra_data().unwrap();
+            assert_eq!(sequential, seek);
+            sequential
+        };
+        assert_eq!(extra(&image), ExtraData::default());
+
+        // padding after the last part is fine, as long as it's zero
+        image.resize(image.len() + 10, 0);
+        assert_eq!(extra(&image), ExtraData::default());
+        image[NIMG_V4_HDR_SIZE + 12] = 1;
+        *image.last_mut().unwrap() = 1;
+        assert_eq!(extra(&image), ExtraData { nonzero_padding: 2, trailing: 0 });
+        image.extend_from_slice(b"more");
+        assert_eq!(extra(&image), ExtraData { nonzero_padding: 2, trailing: 4 });
+    }
+
+    #[test]
+    fn test_bad_parts() {
+        let (_, image) = make_image(&[(0, b"first part"), (16, b"second")]);
+
+        // corrupt data is caught when the part is finished
+        let mut bad = image.clone();
+        bad[NIMG_V4_HDR_SIZE + 17] ^= 0x20;
+        let err = read_all(&bad).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("part 1 is invalid"), "{}", err);
+
+        let err = read_all(&image[..(image.len() - 1)]).unwrap_err();
+        assert_eq!(err.to_string(), "part 1 is truncated: read only 5/6 bytes");
+
+        let (mut header, mut image) = make_image(&[(0, b"first part"), (16, b"second")]);
+        header.parts[1].offset = 4;
+        header.write_to(&mut image[..NIMG_V4_HDR_SIZE]).unwrap();
+        let err = read_all(&image).unwrap_err();
+        assert_eq!(err.to_string(), "part 1 offset 4 is out of order");
+
+        let (_, image) = make_image(&[(0, b"first part"), (100, b"second")]);
+        let mut reader = ImageReader::new(Cursor::new(&image)).unwrap();
+        reader.set_max_padding(89);
+        reader.next_part().unwrap();
+        let err = reader.next_part().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "part 1 has 90 bytes of padding before it, more than the limit of 89"
+        );
+        reader.set_max_padding(90);
+        assert_eq!(reader.scan_extra_data().unwrap(), ExtraData::default());
+    }
+
+    #[test]
+    fn test_offset_overflow() {
+        // offset + size overflows, which has to be an error rather than wrapping around
+        for size in [16u64, 1].iter() {
+            let (mut header, mut image) = make_image(&[(0, b"first part")]);
+            header.parts[0].offset = u64::MAX - 1;
+            header.parts[0].size = *size;
+            header.write_to(&mut image[..NIMG_V4_HDR_SIZE]).unwrap();
+            let err = ImageReader::new(Cursor::new(&image))
+                .and_then(|mut reader| reader.scan_extra_data())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+            let err = ImageReader::new(Cursor::new(&image))
+                .and_then(|mut reader| reader.part(0).map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+/*!
+ * Ed25519 signatures over nImage headers.
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * An image is signed by a detached signature file, conventionally the image filename with
+ * ".sig" appended. The signature covers the complete raw image header, including the part
+ * sizes, offsets, and hashes, so it's only as strong as the part hashes themselves. For v3 images
+ * that's only xxHash32, so only v4 images, which have a cryptographic digest of every part, can
+ * be signed.
+ *
+ * Keys and signatures are stored as hex text, so they're easy to copy around and inspect.
+ * A secret key file holds the 32 byte Ed25519 seed, a public key file holds the 32 byte public
+ * key, and a signature file holds the 64 byte signature.
+ */
+
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+
+use super::format::ImageHeader;
+use super::util::{from_hex, to_hex};
+
+/// Extension appended to an image filename or URL to get its detached signature
+pub const SIGNATURE_EXT: &str = ".sig";
+
+/// Extension of public key files, both for mknImage genkey and in swdl's trusted key directory
+pub const PUBLIC_KEY_EXT: &str = ".pub";
+
+/// Prefix of the signed message, so that these signatures can't be confused with any other
+/// data signed with the same key.
+const SIGNATURE_CONTEXT: &[u8] = b"nImage header signature v1\0";
+
+/// Read a hex-encoded file
+fn read_hex_file(path: &Path) -> Result<Vec<u8>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("failed to read '{}'", path.display()))?;
+    from_hex(&text).ok_or_else(|| anyhow!("'{}' doesn't contain valid hex data", path.display()))
+}
+
+/// The message which is actually signed for a raw image header
+fn signed_message(header: &[u8]) -> Vec<u8> {
+    let mut msg = SIGNATURE_CONTEXT.to_vec();
+    msg.extend_from_slice(header);
+    msg
+}
+
+/// An Ed25519 key pair used for signing images
+pub struct SigningKey(Keypair);
+
+impl SigningKey {
+    /**
+     * Generate a new random key pair, with the seed read from /dev/urandom.
+     */
+    pub fn generate() -> Result<Self> {
+        let mut seed = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+        File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(&mut seed))
+            .context("failed to read random data")?;
+        Ok(Self::from_seed(&seed).unwrap())
+    }
+
+    fn from_seed(seed: &[u8]) -> Option<Self> {
+        let secret = SecretKey::from_bytes(seed).ok()?;
+        let public = PublicKey::from(&secret);
+        Some(SigningKey(Keypair { secret, public }))
+    }
+
+    /**
+     * Load a secret key from a hex-encoded file.
+     */
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_seed(&read_hex_file(path)?)
+            .ok_or_else(|| anyhow!("'{}' isn't a valid Ed25519 secret key", path.display()))
+    }
+
+    /// Hex encoding of the secret key, for saving to a file
+    pub fn secret_hex(&self) -> String {
+        to_hex(self.0.secret.as_bytes())
+    }
+
+    /// Hex encoding of the public key, for saving to a file
+    pub fn public_hex(&self) -> String {
+        to_hex(self.0.public.as_bytes())
+    }
+
+    /**
+     * Sign a raw image header, returning the hex-encoded signature.
+     */
+    pub fn sign_header(&self, header: &[u8]) -> String {
+        to_hex(&self.0.sign(&signed_message(header)).to_bytes())
+    }
+}
+
+/// A public key which is trusted to sign images
+#[derive(Debug)]
+pub struct TrustedKey {
+    /// file the key was loaded from
+    pub path: PathBuf,
+    key: PublicKey,
+}
+
+impl TrustedKey {
+    /**
+     * Load a public key from a hex-encoded file.
+     */
+    pub fn load(path: &Path) -> Result<Self> {
+        let key = PublicKey::from_bytes(&read_hex_file(path)?)
+            .map_err(|_| anyhow!("'{}' isn't a valid Ed25519 public key", path.display()))?;
+        Ok(TrustedKey { path: path.to_path_buf(), key })
+    }
+
+    /**
+     * Load every public key file (named *.pub) in a directory.
+     */
+    pub fn load_dir(dir: &Path) -> Result<Vec<Self>> {
+        let mut keys = Vec::new();
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("failed to read key directory '{}'", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.to_string_lossy().ends_with(PUBLIC_KEY_EXT) {
+                keys.push(Self::load(&path)?);
+            }
+        }
+        keys.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(keys)
+    }
+}
+
+/**
+ * Verify a hex-encoded signature of a raw image header against a list of trusted keys.
+ * Returns the key which made the signature, or an error if none of them did.
+ */
+pub fn verify_header<'a>(
+    header: &[u8],
+    signature: &str,
+    keys: &'a [TrustedKey],
+) -> Result<&'a TrustedKey> {
+    let signature = from_hex(signature)
+        .and_then(|sig| Signature::try_from(sig.as_slice()).ok())
+        .ok_or_else(|| anyhow!("invalid signature format"))?;
+    let msg = signed_message(header);
+    keys.iter()
+        .find(|k| k.key.verify_strict(&msg, &signature).is_ok())
+        .ok_or_else(|| anyhow!("image signature doesn't match any trusted key"))
+}
+
+/**
+ * Check that an image header can be signed, i.e. that it has digests of the part data. This
+ * applies both when signing and after verifying a signature, a valid signature of a v3 header
+ * doesn't say anything about the part data.
+ */
+pub fn check_signable(header: &ImageHeader) -> Result<()> {
+    if header.digest_type.is_none() {
+        return Err(anyhow!(
+            "signed images must be format v4 or later, this image is v{}",
+            header.version
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{CompMode, PartType, NIMG_CURRENT_VERSION};
+    use crate::writer::{ImageWriter, PartOptions};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_sign_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let signer = SigningKey::generate().unwrap();
+        let other = SigningKey::generate().unwrap();
+        fs::write(dir.path().join("other.pub"), other.public_hex()).unwrap();
+        fs::write(dir.path().join("release.pub"), signer.public_hex() + "\n").unwrap();
+        fs::write(dir.path().join("README"), "not a key").unwrap();
+        let keys = TrustedKey::load_dir(dir.path()).unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let mut header = vec![0x5au8; 1024];
+        let sig = signer.sign_header(&header);
+        let key = verify_header(&header, &sig, &keys).unwrap();
+        assert_eq!(key.path, dir.path().join("release.pub"));
+
+        // a secret key saved and loaded again makes the same signatures
+        fs::write(dir.path().join("release.key"), signer.secret_hex()).unwrap();
+        let loaded = SigningKey::load(&dir.path().join("release.key")).unwrap();
+        assert_eq!(loaded.sign_header(&header), sig);
+
+        header[100] ^= 1;
+        assert!(verify_header(&header, &sig, &keys).is_err());
+        assert!(verify_header(&header, "1234", &keys).is_err());
+        assert!(verify_header(&header, &other.sign_header(&header), &keys[1..]).is_err());
+    }
+
+    #[test]
+    fn test_check_signable() {
+        // the same way mknImage create writes an image, then reads it back to sign
+        let write_read = |version: u8| {
+            let mut header = ImageHeader::new("test");
+            header.version = version;
+            if !header.layout().has_digests {
+                header.digest_type = None;
+            }
+            let mut writer = ImageWriter::with_header(Cursor::new(Vec::new()), header).unwrap();
+            writer
+                .add_part(&b"boot"[..], PartType::BootImg, CompMode::None, &PartOptions::default())
+                .unwrap();
+            let image = writer.finish().unwrap().into_inner();
+            ImageHeader::read_from(&mut &image[..]).unwrap().0
+        };
+
+        check_signable(&write_read(NIMG_CURRENT_VERSION)).unwrap();
+        let err = check_signable(&write_read(3)).unwrap_err().to_string();
+        assert!(err.contains("v4 or later"), "{}", err);
+    }
+}
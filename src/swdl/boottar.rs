@@ -0,0 +1,568 @@
+/*!
+ * swdl: Raspberry Pi firmware update engine.
+ * extraction of BootTar parts into the /boot filesystem
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ */
+
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use tar::{Archive, EntryType};
+use tempfile::TempDir;
+use yall::log_macros::*;
+
+/// Prefix of the staging directory name, created as a hidden directory inside the destination
+const STAGING_PREFIX: &str = ".swdl-staging";
+
+/// Limit on the number of symlinks followed when resolving a path, the same as Linux
+const MAX_SYMLINK_HOPS: usize = 40;
+
+#[derive(Debug, Eq, PartialEq)]
+enum StagedKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+/// A single entry extracted to the staging directory, path is relative to the staging root
+#[derive(Debug)]
+struct StagedEntry {
+    path: PathBuf,
+    kind: StagedKind,
+}
+
+/**
+ * A tar archive which has been fully extracted into a staging directory, but not yet moved
+ * into its final location. Dropping this without calling commit() deletes the staging
+ * directory and leaves the destination untouched.
+ */
+#[derive(Debug)]
+pub struct StagedTar {
+    dest: PathBuf,
+    staging: TempDir,
+    entries: Vec<StagedEntry>,
+    size: u64,
+}
+
+/**
+ * Convert a path from a tar entry into a safe relative path. Absolute paths and any ".."
+ * components are rejected, "." components are dropped. Returns None for an empty path.
+ */
+fn sanitize_path(path: &Path) -> Result<Option<PathBuf>> {
+    let mut clean = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::Normal(c) => clean.push(c),
+            Component::CurDir => (),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(anyhow!("unsafe path '{}' in archive", path.display()));
+            }
+        }
+    }
+    Ok(if clean.as_os_str().is_empty() { None } else { Some(clean) })
+}
+
+/**
+ * Check that a symlink at path (relative to the archive root) pointing to target doesn't
+ * resolve to anything outside of the archive root.
+ */
+fn check_symlink_target(path: &Path, target: &Path) -> Result<()> {
+    let err =
+        || anyhow!("symlink '{}' -> '{}' escapes destination", path.display(), target.display());
+    if target.is_absolute() {
+        return Err(err());
+    }
+
+    // depth of the directory containing the link, then walk the target from there
+    let mut depth = path.components().count() as isize - 1;
+    for comp in target.components() {
+        match comp {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir => depth -= 1,
+            Component::RootDir | Component::Prefix(_) => return Err(err()),
+        }
+        if depth < 0 {
+            return Err(err());
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Check that following path (relative to the archive root) doesn't leave the destination,
+ * even through a chain of symlinks. check_symlink_target() only looks at one link at a time,
+ * so e.g. "a/up" -> ".." and "b" -> "a/up/.." each look fine alone. Links are looked up in
+ * the staging directory first and then in the destination, which is what the destination
+ * will look like after commit(). link is the symlink being checked, for error messages.
+ */
+fn check_symlink_chain(staging: &Path, dest: &Path, link: &Path, path: &Path) -> Result<()> {
+    let err =
+        || anyhow!("symlink '{}' escapes destination through another symlink", link.display());
+    let read_link = |rel: &Path| -> Result<Option<PathBuf>> {
+        for root in [staging, dest].iter() {
+            let full = root.join(rel);
+            match fs::symlink_metadata(&full) {
+                Ok(meta) if meta.file_type().is_symlink() => {
+                    return fs::read_link(&full)
+                        .map(Some)
+                        .with_context(|| format!("failed to read symlink '{}'", full.display()));
+                }
+                Ok(_) => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e).context(format!("failed to stat '{}'", full.display())),
+            }
+        }
+        Ok(None)
+    };
+
+    // resolve one component at a time like the kernel does. cur never contains a symlink,
+    // and todo holds the components that are left, in reverse order.
+    let mut cur = PathBuf::new();
+    let mut todo: Vec<PathBuf> =
+        path.components().rev().map(|c| PathBuf::from(c.as_os_str())).collect();
+    let mut hops = 0;
+    while let Some(comp) = todo.pop() {
+        match comp.components().next() {
+            Some(Component::Normal(c)) => {
+                cur.push(c);
+                if let Some(target) = read_link(&cur)? {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS || target.is_absolute() {
+                        return Err(err());
+                    }
+                    cur.pop();
+                    todo.extend(target.components().rev().map(|c| PathBuf::from(c.as_os_str())));
+                }
+            }
+            Some(Component::ParentDir) => {
+                if !cur.pop() {
+                    return Err(err());
+                }
+            }
+            Some(Component::CurDir) | None => (),
+            Some(Component::RootDir) | Some(Component::Prefix(_)) => return Err(err()),
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Make sure that none of the parent directories of rel (relative to root) are symlinks, so
+ * that creating or renaming something at root/rel can't be redirected somewhere else.
+ */
+fn check_no_symlink_parents(root: &Path, rel: &Path) -> Result<()> {
+    let mut cur = root.to_path_buf();
+    if let Some(parent) = rel.parent() {
+        for comp in parent.components() {
+            cur.push(comp);
+            match fs::symlink_metadata(&cur) {
+                Ok(meta) if meta.file_type().is_symlink() => {
+                    return Err(anyhow!("refusing to write through symlink '{}'", cur.display()));
+                }
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e).context(format!("failed to stat '{}'", cur.display())),
+            }
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Remove whatever an earlier entry of the archive staged at path, so that a new entry
+ * replaces it rather than being written through it. A directory is only kept if the new
+ * entry is a directory too.
+ */
+fn remove_staged(path: &Path, rel: &Path, new_dir: bool) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => {
+            if new_dir {
+                Ok(())
+            } else {
+                Err(anyhow!("can't replace directory '{}' in archive", rel.display()))
+            }
+        }
+        Ok(_) => fs::remove_file(path)
+            .with_context(|| format!("failed to replace '{}' in archive", rel.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context(format!("failed to stat '{}'", rel.display())),
+    }
+}
+
+/**
+ * Extract a tar archive into a fresh staging directory inside dest. Nothing outside of
+ * the staging directory is modified until commit() is called on the returned object.
+ *
+ * Only regular files, directories, and symlinks are supported. Ownership and permissions
+ * aren't preserved, since /boot is a FAT filesystem on the Raspberry Pi. FAT doesn't support
+ * symlinks at all, so an archive with symlinks fails to stage there.
+ */
+pub fn stage<R: Read>(reader: R, dest: &Path) -> Result<StagedTar> {
+    let staging = tempfile::Builder::new()
+        .prefix(STAGING_PREFIX)
+        .tempdir_in(dest)
+        .with_context(|| format!("failed to create staging directory in '{}'", dest.display()))?;
+    debug!("staging tar extraction in {}", staging.path().display());
+
+    let mut entries = Vec::new();
+    let mut size = 0u64;
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries().context("failed to read tar archive")? {
+        let mut entry = entry.context("failed to read tar entry")?;
+        let raw_path = entry.path().context("invalid path in tar entry")?.into_owned();
+        let path = match sanitize_path(&raw_path)? {
+            Some(p) => p,
+            None => continue, // "./" or similar, the staging dir itself
+        };
+        check_no_symlink_parents(staging.path(), &path)?;
+        let staged_path = staging.path().join(&path);
+        if let Some(parent) = staged_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory '{}'", parent.display()))?;
+        }
+
+        let kind = match entry.header().entry_type() {
+            EntryType::Directory => {
+                remove_staged(&staged_path, &path, true)?;
+                fs::create_dir_all(&staged_path)
+                    .with_context(|| format!("failed to create directory '{}'", path.display()))?;
+                StagedKind::Dir
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                remove_staged(&staged_path, &path, false)?;
+                // never follow a symlink, even though one can't be here after remove_staged()
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .custom_flags(libc::O_NOFOLLOW)
+                    .open(&staged_path)
+                    .with_context(|| format!("failed to create '{}'", path.display()))?;
+                size += io::copy(&mut entry, &mut file)
+                    .with_context(|| format!("failed to extract '{}'", path.display()))?;
+                file.sync_all()?;
+                StagedKind::File
+            }
+            EntryType::Symlink => {
+                let target = entry
+                    .link_name()
+                    .context("invalid symlink target in tar entry")?
+                    .ok_or_else(|| anyhow!("symlink '{}' has no target", path.display()))?
+                    .into_owned();
+                check_symlink_target(&path, &target)?;
+                let resolved = path.parent().unwrap_or_else(|| Path::new("")).join(&target);
+                check_symlink_chain(staging.path(), dest, &path, &resolved)?;
+                remove_staged(&staged_path, &path, false)?;
+                symlink(&target, &staged_path).map_err(|e| match e.raw_os_error() {
+                    Some(libc::EPERM) => anyhow!(
+                        "can't create symlink '{}', the filesystem at '{}' doesn't support \
+                         symlinks",
+                        path.display(),
+                        dest.display()
+                    ),
+                    _ => {
+                        anyhow!(e).context(format!("failed to create symlink '{}'", path.display()))
+                    }
+                })?;
+                StagedKind::Symlink
+            }
+            // tar entries which are metadata for the next entry, the tar crate already
+            // applied them to the entry's path
+            EntryType::XGlobalHeader | EntryType::XHeader | EntryType::GNULongName => continue,
+            t => {
+                return Err(anyhow!("unsupported entry type {:?} for '{}'", t, path.display()));
+            }
+        };
+
+        trace!("staged {:?} {}", kind, path.display());
+        // a later entry for the same path replaced the earlier one
+        entries.retain(|e: &StagedEntry| e.path != path);
+        entries.push(StagedEntry { path, kind });
+    }
+
+    // each link was checked against the ones before it, but one that comes later in the
+    // archive can still redirect an earlier link, so check them all again
+    for entry in entries.iter().filter(|e| e.kind == StagedKind::Symlink) {
+        check_symlink_chain(staging.path(), dest, &entry.path, &entry.path)?;
+    }
+
+    Ok(StagedTar { dest: dest.to_path_buf(), staging, entries, size })
+}
+
+impl StagedTar {
+    /**
+     * Move every staged entry into the destination directory. Each file is swapped in with
+     * an atomic rename(), so every individual file is always either the old or new version.
+     * Files in the destination which aren't in the archive are left alone.
+     * Returns the total number of file bytes extracted.
+     */
+    pub fn commit(self) -> Result<u64> {
+        let mut dirs = BTreeSet::new();
+        dirs.insert(self.dest.clone());
+
+        for entry in self.entries.iter() {
+            check_no_symlink_parents(&self.dest, &entry.path)?;
+            let src = self.staging.path().join(&entry.path);
+            let dst = self.dest.join(&entry.path);
+            let dst_meta = fs::symlink_metadata(&dst).ok();
+
+            if entry.kind == StagedKind::Dir {
+                match dst_meta {
+                    Some(ref m) if m.is_dir() => (),
+                    Some(_) => {
+                        fs::remove_file(&dst).with_context(|| {
+                            format!("failed to replace '{}' with a directory", dst.display())
+                        })?;
+                        fs::create_dir(&dst)?;
+                    }
+                    None => fs::create_dir_all(&dst).with_context(|| {
+                        format!("failed to create directory '{}'", dst.display())
+                    })?,
+                }
+                continue;
+            }
+
+            if let Some(ref m) = dst_meta {
+                if m.is_dir() {
+                    return Err(anyhow!("can't replace directory '{}' with a file", dst.display()));
+                }
+            }
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)?;
+                dirs.insert(parent.to_path_buf());
+            }
+            fs::rename(&src, &dst)
+                .with_context(|| format!("failed to move '{}' into place", dst.display()))?;
+        }
+
+        // make sure the renames are on disk before we report success
+        for dir in dirs.iter() {
+            File::open(dir)
+                .and_then(|d| d.sync_all())
+                .with_context(|| format!("failed to sync directory '{}'", dir.display()))?;
+        }
+
+        debug!("committed {} tar entries to {}", self.entries.len(), self.dest.display());
+        Ok(self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nimage::assert_matches;
+
+    use tar::{Builder, Header};
+
+    /// Build a header for path, writing the raw name bytes so that tar::Header's own
+    /// path validation doesn't stop us from making malicious archives.
+    fn raw_header(path: &str, etype: EntryType, size: u64) -> Header {
+        let mut header = Header::new_ustar();
+        header.as_ustar_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(etype);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_cksum();
+        header
+    }
+
+    fn add_file(builder: &mut Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let header = raw_header(path, EntryType::Regular, data.len() as u64);
+        builder.append(&header, data).unwrap();
+    }
+
+    fn add_symlink(builder: &mut Builder<Vec<u8>>, path: &str, target: &str) {
+        let mut header = raw_header(path, EntryType::Symlink, 0);
+        header.set_link_name(target).unwrap();
+        header.set_cksum();
+        builder.append(&header, io::empty()).unwrap();
+    }
+
+    fn finish(builder: Builder<Vec<u8>>) -> Vec<u8> {
+        builder.into_inner().unwrap()
+    }
+
+    /// count entries in dir whose name starts with the staging prefix
+    fn staging_dirs(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| {
+                e.as_ref().unwrap().file_name().to_string_lossy().starts_with(STAGING_PREFIX)
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(sanitize_path(Path::new("./a/b")).unwrap(), Some(PathBuf::from("a/b")));
+        assert_eq!(sanitize_path(Path::new("./")).unwrap(), None);
+        assert!(sanitize_path(Path::new("../a")).is_err());
+        assert!(sanitize_path(Path::new("a/../../b")).is_err());
+        assert!(sanitize_path(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_check_symlink_target() {
+        assert!(check_symlink_target(Path::new("a"), Path::new("b")).is_ok());
+        assert!(check_symlink_target(Path::new("dir/a"), Path::new("../b")).is_ok());
+        assert!(check_symlink_target(Path::new("a"), Path::new("../b")).is_err());
+        assert!(check_symlink_target(Path::new("dir/a"), Path::new("x/../../../b")).is_err());
+        assert!(check_symlink_target(Path::new("a"), Path::new("/boot/b")).is_err());
+    }
+
+    #[test]
+    fn test_extract() {
+        let boot = tempfile::tempdir().unwrap();
+        fs::write(boot.path().join("config.txt"), b"old config").unwrap();
+        fs::write(boot.path().join("keep.txt"), b"not in the archive").unwrap();
+
+        let mut builder = Builder::new(Vec::new());
+        add_file(&mut builder, "./config.txt", b"new config");
+        add_file(&mut builder, "overlays/foo.dtbo", b"overlay");
+        add_symlink(&mut builder, "overlays/bar.dtbo", "foo.dtbo");
+        let data = finish(builder);
+
+        let staged = stage(data.as_slice(), boot.path()).unwrap();
+        // nothing should be swapped in until commit
+        assert_eq!(fs::read(boot.path().join("config.txt")).unwrap(), b"old config");
+        assert_eq!(staging_dirs(boot.path()), 1);
+
+        assert_eq!(staged.commit().unwrap(), 17);
+        assert_eq!(fs::read(boot.path().join("config.txt")).unwrap(), b"new config");
+        assert_eq!(fs::read(boot.path().join("keep.txt")).unwrap(), b"not in the archive");
+        assert_eq!(fs::read(boot.path().join("overlays/foo.dtbo")).unwrap(), b"overlay");
+        assert_eq!(fs::read(boot.path().join("overlays/bar.dtbo")).unwrap(), b"overlay");
+        assert_eq!(staging_dirs(boot.path()), 0);
+    }
+
+    #[test]
+    fn test_extract_dropped() {
+        let boot = tempfile::tempdir().unwrap();
+        let mut builder = Builder::new(Vec::new());
+        add_file(&mut builder, "kernel.img", b"kernel");
+        let staged = stage(finish(builder).as_slice(), boot.path()).unwrap();
+        std::mem::drop(staged);
+        assert!(!boot.path().join("kernel.img").exists());
+        assert_eq!(staging_dirs(boot.path()), 0);
+    }
+
+    #[test]
+    fn test_extract_traversal() {
+        let top = tempfile::tempdir().unwrap();
+        let boot = top.path().join("boot");
+        fs::create_dir(&boot).unwrap();
+
+        let mut builder = Builder::new(Vec::new());
+        add_file(&mut builder, "../evil", b"evil");
+        assert_matches!(stage(finish(builder).as_slice(), &boot), Err(_));
+        assert!(!top.path().join("evil").exists());
+        assert_eq!(staging_dirs(&boot), 0);
+
+        let mut builder = Builder::new(Vec::new());
+        add_symlink(&mut builder, "escape", "../..");
+        assert_matches!(stage(finish(builder).as_slice(), &boot), Err(_));
+
+        let mut builder = Builder::new(Vec::new());
+        add_symlink(&mut builder, "escape", "/etc");
+        assert_matches!(stage(finish(builder).as_slice(), &boot), Err(_));
+    }
+
+    #[test]
+    fn test_extract_symlink_chain() {
+        let top = tempfile::tempdir().unwrap();
+        let boot = top.path().join("boot");
+        fs::create_dir(&boot).unwrap();
+
+        // each link stays inside boot on its own, but "escape" goes through "dir/up", in
+        // either order in the archive
+        for order in [["dir/up", "escape"], ["escape", "dir/up"]].iter() {
+            let mut builder = Builder::new(Vec::new());
+            for name in order.iter() {
+                match *name {
+                    "dir/up" => add_symlink(&mut builder, "dir/up", ".."),
+                    _ => add_symlink(&mut builder, "escape", "dir/up/.."),
+                }
+            }
+            assert_matches!(stage(finish(builder).as_slice(), &boot), Err(_));
+            assert_eq!(staging_dirs(&boot), 0);
+        }
+
+        // a loop of links can't be resolved either
+        let mut builder = Builder::new(Vec::new());
+        add_symlink(&mut builder, "a", "b");
+        add_symlink(&mut builder, "b", "a");
+        assert_matches!(stage(finish(builder).as_slice(), &boot), Err(_));
+
+        // an existing link in the destination counts too
+        symlink("/etc", boot.join("etc")).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        add_symlink(&mut builder, "passwd", "etc/passwd");
+        assert_matches!(stage(finish(builder).as_slice(), &boot), Err(_));
+
+        // chains which stay inside are fine
+        let mut builder = Builder::new(Vec::new());
+        add_file(&mut builder, "overlays/foo.dtbo", b"overlay");
+        add_symlink(&mut builder, "ov", "overlays");
+        add_symlink(&mut builder, "overlays/up", "..");
+        add_symlink(&mut builder, "foo.dtbo", "ov/up/ov/foo.dtbo");
+        stage(finish(builder).as_slice(), &boot).unwrap().commit().unwrap();
+        assert_eq!(fs::read(boot.join("foo.dtbo")).unwrap(), b"overlay");
+    }
+
+    #[test]
+    fn test_extract_replace_symlink() {
+        let top = tempfile::tempdir().unwrap();
+        let boot = top.path().join("boot");
+        fs::create_dir(&boot).unwrap();
+
+        // every link stays inside on its own, but "b" goes up through "a", and then the file
+        // "d" would be written through "d" -> "b" -> ".."
+        let mut builder = Builder::new(Vec::new());
+        add_symlink(&mut builder, "a", ".");
+        add_symlink(&mut builder, "b", "a/..");
+        add_symlink(&mut builder, "d", "b/../evil");
+        add_file(&mut builder, "d", b"evil");
+        assert_matches!(stage(finish(builder).as_slice(), &boot), Err(_));
+        assert!(!top.path().join("evil").exists());
+        assert_eq!(staging_dirs(&boot), 0);
+
+        // a file replaces a link that was staged before it, rather than being written to the
+        // link's target
+        let mut builder = Builder::new(Vec::new());
+        add_symlink(&mut builder, "kernel.img", "other.img");
+        add_file(&mut builder, "kernel.img", b"kernel");
+        stage(finish(builder).as_slice(), &boot).unwrap().commit().unwrap();
+        assert!(fs::symlink_metadata(boot.join("kernel.img")).unwrap().is_file());
+        assert_eq!(fs::read(boot.join("kernel.img")).unwrap(), b"kernel");
+        assert!(!boot.join("other.img").exists());
+    }
+
+    #[test]
+    fn test_extract_symlink_parent() {
+        let top = tempfile::tempdir().unwrap();
+        let boot = top.path().join("boot");
+        let outside = top.path().join("outside");
+        fs::create_dir(&boot).unwrap();
+        fs::create_dir(&outside).unwrap();
+
+        // a symlink within the archive can't be used as a directory
+        let mut builder = Builder::new(Vec::new());
+        add_symlink(&mut builder, "link", ".");
+        add_file(&mut builder, "link/file", b"data");
+        assert_matches!(stage(finish(builder).as_slice(), &boot), Err(_));
+
+        // an existing symlink in the destination can't be written through either
+        symlink(&outside, boot.join("link")).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        add_file(&mut builder, "link/file", b"data");
+        let staged = stage(finish(builder).as_slice(), &boot).unwrap();
+        assert_matches!(staged.commit(), Err(_));
+        assert!(!outside.join("file").exists());
+    }
+}
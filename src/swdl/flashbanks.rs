@@ -10,11 +10,342 @@
 #![cfg_attr(target_arch = "x86_64", allow(dead_code))]
 #![cfg_attr(target_arch = "x86_64", allow(unused_imports))]
 
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
 use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+use yall::log_macros::*;
 
 use nimage::format::PartType;
 
-const ROOTFS_DEVS: [&str; 2] = ["/dev/mmcblk0p2", "/dev/mmcblk0p3"];
+use crate::mounts::MountPolicy;
+use crate::parttable;
+
+/// Default mount point of the boot partition, where BootTar parts are extracted and
+/// cmdline.txt lives.
+#[cfg(not(target_arch = "x86_64"))]
+pub const DEFAULT_BOOT_DIR: &str = "/boot";
+
+/// Default mount point of the boot partition, where BootTar parts are extracted and
+/// cmdline.txt lives.
+/// on x86, use a scratch directory rather than the host's /boot
+#[cfg(target_arch = "x86_64")]
+pub const DEFAULT_BOOT_DIR: &str = "/tmp/swdl-boot";
+
+/// File in the boot directory where swdl records the progress of updates by default. It has
+/// to be somewhere that every rootfs bank can see, and the rootfs may be read-only.
+pub const DEFAULT_STATE_FILE_NAME: &str = "swdl-state.json";
+
+/// Bank layout config file which is loaded if it exists, otherwise the defaults are used
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/swdl.toml";
+
+/// Default boot partition device, the SD card on a Raspberry Pi
+const DEFAULT_BOOT_DEVICE: &str = "/dev/mmcblk0p1";
+
+/// Default A/B rootfs devices, the SD card on a Raspberry Pi
+const DEFAULT_ROOTFS_DEVS: [&str; 2] = ["/dev/mmcblk0p2", "/dev/mmcblk0p3"];
+
+/// Config file which the Raspberry Pi firmware reads instead of config.txt for one boot after
+/// `reboot '0 tryboot'`
+pub const TRYBOOT_CONFIG: &str = "tryboot.txt";
+
+/// Kernel cmdline file for the new rootfs, selected by TRYBOOT_CONFIG
+pub const TRYBOOT_CMDLINE: &str = "cmdline-tryboot.txt";
+
+/// Directory of udev's by-label, by-uuid, etc. symlinks to block devices
+const DEV_DISK_DIR: &str = "/dev/disk";
+
+/// Identifier types which can be used as root=TYPE=value on the kernel cmdline
+const ROOT_ID_TYPES: [&str; 4] = ["PARTUUID", "PARTLABEL", "UUID", "LABEL"];
+
+/**
+ * Split a root= value like "PARTUUID=6c586e13-02" into the identifier type and value.
+ * Returns None for a plain device path or an unrecognized identifier type.
+ */
+fn split_root_id(root: &str) -> Option<(&str, &str)> {
+    let eq = root.find('=')?;
+    let (id_type, value) = (&root[..eq], &root[(eq + 1)..]);
+    if ROOT_ID_TYPES.contains(&id_type) {
+        Some((id_type, value))
+    } else {
+        None
+    }
+}
+
+/// Compare two identifiers of type id_type. UUIDs are compared case-insensitively, since
+/// they're hex strings, but labels are case-sensitive.
+fn id_eq(id_type: &str, a: &str, b: &str) -> bool {
+    if id_type.ends_with("UUID") {
+        a.eq_ignore_ascii_case(b)
+    } else {
+        a == b
+    }
+}
+
+/**
+ * Split a partition device path into the path of the whole disk and the partition number,
+ * e.g. /dev/sda2 => (/dev/sda, 2) and /dev/mmcblk0p2 => (/dev/mmcblk0, 2)
+ */
+fn split_partition_path(device: &str) -> Option<(&str, u32)> {
+    let base = device.trim_end_matches(|c: char| c.is_ascii_digit());
+    let number = device[base.len()..].parse().ok()?;
+    // devices whose name ends in a digit (mmcblk0, nvme0n1, loop0) have a 'p' separator
+    let disk = match base.strip_suffix('p') {
+        Some(d) if d.ends_with(|c: char| c.is_ascii_digit()) => d,
+        _ => base,
+    };
+    if disk.is_empty() {
+        None
+    } else {
+        Some((disk, number))
+    }
+}
+
+/**
+ * Look up a PARTUUID or PARTLABEL by reading the partition table of device's disk.
+ */
+fn partition_table_id(device: &str, id_type: &str) -> Option<String> {
+    let (disk, number) = split_partition_path(device)?;
+    let parts = File::open(disk)
+        .and_then(|mut f| parttable::read_partitions(&mut f))
+        .map_err(|e| debug!("failed to read partition table of {}: {}", disk, e))
+        .ok()?;
+    let part = parts.into_iter().find(|p| p.number == number)?;
+    match id_type {
+        "PARTUUID" => Some(part.partuuid),
+        "PARTLABEL" => part.partlabel,
+        _ => None,
+    }
+}
+
+/**
+ * Look up any type of identifier of device from udev's symlinks in dev_disk_dir, e.g.
+ * /dev/disk/by-label/rootfs_a -> ../../sda2
+ */
+fn udev_id(dev_disk_dir: &Path, device: &str, id_type: &str) -> Option<String> {
+    let device = fs::canonicalize(device).ok()?;
+    let dir = dev_disk_dir.join(format!("by-{}", id_type.to_ascii_lowercase()));
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| fs::canonicalize(entry.path()).ok().as_ref() == Some(&device))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+}
+
+/**
+ * One rootfs bank. The device path is where the rootfs gets written, the other optional
+ * identifiers are used to recognize the bank in a root=PARTUUID=... or root=LABEL=...
+ * kernel command line argument. If they're not set, they're looked up from the partition
+ * table or /dev/disk/by-* symlinks instead.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RootfsBank {
+    /// block device path, e.g. /dev/mmcblk0p2
+    pub device: String,
+    /// partition UUID, as in root=PARTUUID=...
+    #[serde(default)]
+    pub partuuid: Option<String>,
+    /// filesystem label, as in root=LABEL=...
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+impl RootfsBank {
+    fn new(device: &str) -> Self {
+        RootfsBank { device: device.to_string(), ..Default::default() }
+    }
+
+    /// Get an identifier for this bank which was explicitly set in the config file
+    fn configured_id(&self, id_type: &str) -> Option<&str> {
+        match id_type {
+            "PARTUUID" => self.partuuid.as_deref(),
+            "LABEL" => self.label.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Layout of the flash devices that swdl programs: the boot partition and the set of rootfs
+ * banks which are cycled through on each update. Loaded from a TOML file like this, where
+ * every field is optional and defaults to the Raspberry Pi SD card layout.
+ *
+ * ```toml
+ * boot_device = "/dev/sda1"
+ * boot_dir = "/boot"
+ * state_file = "/boot/swdl-state.json"
+ * tryboot = true
+ * mounted = "remount"
+ *
+ * [[rootfs]]
+ * device = "/dev/sda2"
+ * partuuid = "6c586e13-02"
+ *
+ * [[rootfs]]
+ * device = "/dev/sda3"
+ * partuuid = "6c586e13-03"
+ * ```
+ */
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BankLayout {
+    /// block device of the boot partition, where BootImg parts are written
+    pub boot_device: String,
+    /// mount point of the boot partition
+    pub boot_dir: PathBuf,
+    /// rootfs banks, at least two
+    pub rootfs: Vec<RootfsBank>,
+    /// where the update state is saved, see the state module. Use state_file() to get the
+    /// default of DEFAULT_STATE_FILE_NAME in boot_dir if this isn't set.
+    pub state_file: Option<PathBuf>,
+    /// boot the new rootfs once with the firmware's tryboot mechanism, rather than switching
+    /// to it permanently right away. Needs a Raspberry Pi 4 or newer.
+    pub tryboot: bool,
+    /// what to do if a device is mounted when it's about to be programmed, "refuse" or
+    /// "remount". The running rootfs is always refused.
+    pub mounted: MountPolicy,
+    /// where to look for /dev/disk/by-* symlinks, not configurable except in tests
+    #[serde(skip)]
+    dev_disk_dir: PathBuf,
+}
+
+impl Default for BankLayout {
+    fn default() -> Self {
+        BankLayout {
+            boot_device: DEFAULT_BOOT_DEVICE.to_string(),
+            boot_dir: PathBuf::from(DEFAULT_BOOT_DIR),
+            rootfs: DEFAULT_ROOTFS_DEVS.iter().map(|d| RootfsBank::new(d)).collect(),
+            state_file: None,
+            tryboot: false,
+            mounted: MountPolicy::default(),
+            dev_disk_dir: PathBuf::from(DEV_DISK_DIR),
+        }
+    }
+}
+
+impl FromStr for BankLayout {
+    type Err = anyhow::Error;
+    /**
+     * Parse and validate a bank layout from a TOML string.
+     */
+    fn from_str(s: &str) -> Result<Self> {
+        let layout: BankLayout = toml::from_str(s)?;
+        layout.validate()?;
+        Ok(layout)
+    }
+}
+
+impl BankLayout {
+    /**
+     * Load the bank layout from the config file at path. If path is None, then load
+     * DEFAULT_CONFIG_PATH if it exists, or fall back to the default layout otherwise.
+     */
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(p) => p,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => {
+                debug!("{} not found, using default bank layout", DEFAULT_CONFIG_PATH);
+                return Ok(Self::default());
+            }
+        };
+
+        debug!("loading bank layout from {}", path.display());
+        fs::read_to_string(path)
+            .map_err(anyhow::Error::new)
+            .and_then(|s| s.parse())
+            .with_context(|| format!("failed to load bank layout from '{}'", path.display()))
+    }
+
+    /**
+     * Path of the update state file, from the config or in the boot directory by default.
+     */
+    pub fn state_file(&self) -> PathBuf {
+        self.state_file.clone().unwrap_or_else(|| self.boot_dir.join(DEFAULT_STATE_FILE_NAME))
+    }
+
+    /**
+     * Check that there are at least two rootfs banks and that no device is listed twice.
+     */
+    pub fn validate(&self) -> Result<()> {
+        if self.rootfs.len() < 2 {
+            return Err(anyhow!(
+                "at least 2 rootfs banks are required, found {}",
+                self.rootfs.len()
+            ));
+        }
+        for (i, bank) in self.rootfs.iter().enumerate() {
+            if bank.device.is_empty() {
+                return Err(anyhow!("rootfs bank {} has an empty device path", i));
+            }
+            if bank.device == self.boot_device
+                || self.rootfs[..i].iter().any(|b| b.device == bank.device)
+            {
+                return Err(anyhow!("device {} is used more than once", bank.device));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Get an identifier of type id_type (e.g. "PARTUUID") for a rootfs bank. Values in the
+     * config file take priority, otherwise PARTUUID and PARTLABEL are read from the partition
+     * table, and anything else is looked up in the /dev/disk/by-* symlinks.
+     */
+    pub fn bank_id(&self, bank: &RootfsBank, id_type: &str) -> Option<String> {
+        if let Some(id) = bank.configured_id(id_type) {
+            return Some(id.to_string());
+        }
+        let id = match id_type {
+            "PARTUUID" | "PARTLABEL" => partition_table_id(&bank.device, id_type),
+            _ => None,
+        };
+        id.or_else(|| udev_id(&self.dev_disk_dir, &bank.device, id_type))
+    }
+
+    /**
+     * Find the index of the currently active rootfs bank, based on the root= argument of
+     * the kernel cmdline, which may be a device path or an identifier like PARTUUID=...
+     */
+    pub fn active_rootfs(&self, cmdline: &str) -> Option<usize> {
+        let active = get_active_rootfs(cmdline)?;
+        match split_root_id(active) {
+            Some((id_type, value)) => self.rootfs.iter().position(
+                |b| matches!(self.bank_id(b, id_type), Some(id) if id_eq(id_type, &id, value)),
+            ),
+            None => self.rootfs.iter().position(|b| b.device == active),
+        }
+    }
+
+    /**
+     * Get the root= value for a bank using the same style of identifier as the current
+     * root= value in cmdline, falling back to the device path if the bank's identifier
+     * of that type can't be found.
+     */
+    pub fn root_spec(&self, bank: &RootfsBank, cmdline: &str) -> String {
+        if let Some((id_type, _)) = get_active_rootfs(cmdline).and_then(split_root_id) {
+            match self.bank_id(bank, id_type) {
+                Some(id) => return format!("{}={}", id_type, id),
+                None => warn!("no {} found for {}, using the device path", id_type, bank.device),
+            }
+        }
+        bank.device.clone()
+    }
+
+    /**
+     * Get the next rootfs bank after the currently active one, i.e. the one which should be
+     * programmed on this update.
+     */
+    pub fn inactive_rootfs(&self, cmdline: &str) -> Option<&RootfsBank> {
+        let active = self.active_rootfs(cmdline)?;
+        Some(&self.rootfs[(active + 1) % self.rootfs.len()])
+    }
+}
 
 pub fn get_cmdline() -> std::io::Result<String> {
     std::fs::read_to_string("/proc/cmdline")
@@ -31,30 +362,23 @@ pub fn get_active_rootfs(cmdline: &str) -> Option<&str> {
     None
 }
 
-pub fn get_inactive_rootfs(cmdline: &str) -> Option<&'static str> {
-    let active = get_active_rootfs(cmdline)?;
-    for (i, dev) in ROOTFS_DEVS.iter().enumerate() {
-        if active == *dev {
-            return Some(ROOTFS_DEVS[(i + 1) % ROOTFS_DEVS.len()]);
-        }
-    }
-    None
-}
-
 /// Get the destination path for a raw PartType
 #[cfg(not(target_arch = "x86_64"))]
-pub fn raw_dest_path(ptype: PartType) -> Result<&'static str> {
+pub fn raw_dest_path(layout: &BankLayout, ptype: PartType) -> Result<&str> {
     const NOT_FOUND_MSG: &str =
         "failed to get inactive rootfs. root= in /proc/cmdline is missing or unrecognized";
 
     match ptype {
-        PartType::BootImg => Ok("/dev/mmcblk0p1"),
+        PartType::BootImg => Ok(&layout.boot_device),
         PartType::Rootfs | PartType::RootfsRw => {
             let cmdline = get_cmdline().with_context(|| "failed to get kernel cmdline")?;
-            get_inactive_rootfs(&cmdline).ok_or_else(|| anyhow!(NOT_FOUND_MSG))
+            layout
+                .inactive_rootfs(&cmdline)
+                .map(|b| b.device.as_str())
+                .ok_or_else(|| anyhow!(NOT_FOUND_MSG))
         }
         PartType::BootTar | PartType::Invalid => {
-            Err(anyhow!("Part type {} is not a raw partition"))
+            Err(anyhow!("Part type {} is not a raw partition", ptype))
         }
     }
 }
@@ -62,16 +386,33 @@ pub fn raw_dest_path(ptype: PartType) -> Result<&'static str> {
 /// Get the destination path for a raw PartType
 /// on x86, always write to /dev/null
 #[cfg(target_arch = "x86_64")]
-pub fn raw_dest_path(ptype: PartType) -> Result<&'static str> {
+pub fn raw_dest_path(_layout: &BankLayout, ptype: PartType) -> Result<&str> {
     match ptype {
         PartType::BootImg | PartType::Rootfs | PartType::RootfsRw => Ok("/dev/null"),
         PartType::BootTar | PartType::Invalid => {
-            Err(anyhow!("Part type {} is not a raw partition"))
+            Err(anyhow!("Part type {} is not a raw partition", ptype))
         }
     }
 }
 
-#[allow(dead_code)] // FIXME
+/// Get the root= value for the newly programmed rootfs, i.e. the current inactive bank,
+/// in the same style as the current root= value.
+#[cfg(not(target_arch = "x86_64"))]
+pub fn new_root_spec(layout: &BankLayout) -> Result<String> {
+    let cmdline = get_cmdline().with_context(|| "failed to get kernel cmdline")?;
+    let bank = layout.inactive_rootfs(&cmdline).ok_or_else(|| {
+        anyhow!("failed to get inactive rootfs. root= in /proc/cmdline is missing or unrecognized")
+    })?;
+    Ok(layout.root_spec(bank, &cmdline))
+}
+
+/// Get the root= value for the newly programmed rootfs
+/// on x86, match raw_dest_path and always use /dev/null
+#[cfg(target_arch = "x86_64")]
+pub fn new_root_spec(_layout: &BankLayout) -> Result<String> {
+    Ok(String::from("/dev/null"))
+}
+
 pub fn update_rootfs(cmdline: &str, new_rootfs: &str, rw: bool) -> String {
     let new_rootfs_word = format!("root={}", new_rootfs);
     let mut set_root = false;
@@ -104,6 +445,111 @@ pub fn update_rootfs(cmdline: &str, new_rootfs: &str, rw: bool) -> String {
     new.join(" ")
 }
 
+/// Get the root= value of the rootfs that's running now
+#[cfg(not(target_arch = "x86_64"))]
+pub fn running_root_spec() -> Result<String> {
+    let cmdline = get_cmdline().with_context(|| "failed to get kernel cmdline")?;
+    get_active_rootfs(&cmdline)
+        .map(String::from)
+        .ok_or_else(|| anyhow!("root= is missing from /proc/cmdline"))
+}
+
+/// Get the root= value of the rootfs that's running now
+/// on x86, match new_root_spec so that the new image always looks like it's running
+#[cfg(target_arch = "x86_64")]
+pub fn running_root_spec() -> Result<String> {
+    Ok(String::from("/dev/null"))
+}
+
+/// Read the kernel cmdline file from the boot partition
+pub fn read_cmdline(boot_dir: &Path) -> Result<String> {
+    let path = boot_dir.join("cmdline.txt");
+    fs::read_to_string(&path).with_context(|| format!("failed to read '{}'", path.display()))
+}
+
+/**
+ * Replace the file name in boot_dir with contents. The new file is written to a temporary
+ * file, synced, then renamed over the old one, so it's never left partially written.
+ */
+pub fn write_boot_file(boot_dir: &Path, name: &str, contents: &str) -> Result<()> {
+    let path = boot_dir.join(name);
+    let mut tmp = NamedTempFile::new_in(boot_dir)
+        .with_context(|| format!("failed to create temp file in '{}'", boot_dir.display()))?;
+    tmp.write_all(contents.as_bytes())
+        .and_then(|_| tmp.as_file().sync_all())
+        .with_context(|| format!("failed to write '{}'", tmp.path().display()))?;
+    tmp.persist(&path).with_context(|| format!("failed to replace '{}'", path.display()))?;
+
+    // sync the directory so the rename itself is on disk
+    File::open(boot_dir)
+        .and_then(|d| d.sync_all())
+        .with_context(|| format!("failed to sync directory '{}'", boot_dir.display()))
+}
+
+/**
+ * Point the bootloader at a new rootfs by rewriting cmdline.txt in boot_dir with new_rootfs
+ * as the root device, mounted read-write if rw is set or read-only otherwise.
+ */
+pub fn switch_rootfs(boot_dir: &Path, new_rootfs: &str, rw: bool) -> Result<()> {
+    let new_cmdline = update_rootfs(&read_cmdline(boot_dir)?, new_rootfs, rw);
+    debug!("new cmdline: {}", new_cmdline);
+    write_boot_file(boot_dir, "cmdline.txt", &(new_cmdline + "\n"))?;
+    info!(
+        "Updated {} with root={} {}",
+        boot_dir.join("cmdline.txt").display(),
+        new_rootfs,
+        if rw { "rw" } else { "ro" }
+    );
+    Ok(())
+}
+
+/**
+ * Set up the firmware's tryboot mechanism to boot new_rootfs once. After `reboot '0 tryboot'`
+ * the firmware reads tryboot.txt instead of config.txt, which is a copy of config.txt that
+ * selects a cmdline file for the new rootfs. Any other reboot still runs the current rootfs,
+ * so if the new image doesn't boot then power cycling goes back to the old one.
+ */
+pub fn stage_tryboot(boot_dir: &Path, new_rootfs: &str, rw: bool) -> Result<()> {
+    let new_cmdline = update_rootfs(&read_cmdline(boot_dir)?, new_rootfs, rw);
+    debug!("tryboot cmdline: {}", new_cmdline);
+    let config_path = boot_dir.join("config.txt");
+    let config = match fs::read_to_string(&config_path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read '{}'", config_path.display()))
+        }
+    };
+
+    // The cmdline file goes first, so that tryboot.txt never refers to a missing file. The
+    // [all] filter makes sure that cmdline= applies no matter how config.txt ends.
+    write_boot_file(boot_dir, TRYBOOT_CMDLINE, &(new_cmdline + "\n"))?;
+    let separator = if config.is_empty() || config.ends_with('\n') { "" } else { "\n" };
+    let tryboot_config =
+        format!("{}{}\n# added by swdl\n[all]\ncmdline={}\n", config, separator, TRYBOOT_CMDLINE);
+    write_boot_file(boot_dir, TRYBOOT_CONFIG, &tryboot_config)?;
+    info!("Set up tryboot with root={} {}", new_rootfs, if rw { "rw" } else { "ro" });
+    Ok(())
+}
+
+/**
+ * Remove the tryboot files written by stage_tryboot(), if they exist.
+ */
+pub fn clear_tryboot(boot_dir: &Path) -> Result<()> {
+    // tryboot.txt first, for the same reason it's written last
+    for name in [TRYBOOT_CONFIG, TRYBOOT_CMDLINE].iter() {
+        let path = boot_dir.join(name);
+        match fs::remove_file(&path) {
+            Ok(()) => debug!("removed {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to remove '{}'", path.display()))
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,15 +568,127 @@ mod tests {
         assert_eq!(get_active_rootfs(""), None);
     }
 
+    /// wrapper for BankLayout::inactive_rootfs that returns only the device path
+    fn inactive_dev<'a>(layout: &'a BankLayout, cmdline: &str) -> Option<&'a str> {
+        layout.inactive_rootfs(cmdline).map(|b| b.device.as_str())
+    }
+
     #[test]
     fn test_inactive_rootfs() {
-        assert_eq!(get_inactive_rootfs(LONG_CMDLINE), Some("/dev/mmcblk0p2"));
+        let layout = BankLayout::default();
+        assert_eq!(inactive_dev(&layout, LONG_CMDLINE), Some("/dev/mmcblk0p2"));
         assert_eq!(
-            get_inactive_rootfs("foo bar root=/dev/mmcblk0p2 ro asdf=asdf"),
+            inactive_dev(&layout, "foo bar root=/dev/mmcblk0p2 ro asdf=asdf"),
             Some("/dev/mmcblk0p3")
         );
-        assert_eq!(get_inactive_rootfs(""), None);
-        assert_eq!(get_inactive_rootfs("test root=/dev/sda1 rw"), None);
+        assert_eq!(inactive_dev(&layout, ""), None);
+        assert_eq!(inactive_dev(&layout, "test root=/dev/sda1 rw"), None);
+    }
+
+    #[test]
+    fn test_bank_layout() {
+        let layout: BankLayout = "\
+            boot_device = '/dev/sda1'
+            [[rootfs]]
+            device = '/dev/sda2'
+            partuuid = '6c586e13-02'
+            [[rootfs]]
+            device = '/dev/sda3'
+            partuuid = '6C586E13-03'
+            label = 'rootfs_b'
+        "
+        .parse()
+        .unwrap();
+
+        assert_eq!(layout.boot_device, "/dev/sda1");
+        assert_eq!(layout.boot_dir, Path::new(DEFAULT_BOOT_DIR));
+        assert_eq!(inactive_dev(&layout, "root=/dev/sda2 ro"), Some("/dev/sda3"));
+        assert_eq!(inactive_dev(&layout, "root=PARTUUID=6c586e13-03 ro"), Some("/dev/sda2"));
+        assert_eq!(inactive_dev(&layout, "root=PARTUUID=6C586E13-02 ro"), Some("/dev/sda3"));
+        assert_eq!(inactive_dev(&layout, "root=LABEL=rootfs_b ro"), Some("/dev/sda2"));
+        assert_eq!(inactive_dev(&layout, "root=PARTUUID=6c586e13-04"), None);
+        assert_eq!(inactive_dev(&layout, "root=/dev/mmcblk0p2"), None);
+
+        // an empty config is the default layout
+        assert_eq!("".parse::<BankLayout>().unwrap(), BankLayout::default());
+
+        // invalid layouts
+        assert!("[[rootfs]]\ndevice = '/dev/sda2'".parse::<BankLayout>().is_err());
+        assert!("rootfs = [{device = 'a'}, {device = 'a'}]".parse::<BankLayout>().is_err());
+        assert!("boot_device = 'a'\nrootfs = [{device = 'a'}, {device = 'b'}]"
+            .parse::<BankLayout>()
+            .is_err());
+        assert!("bogus = 1".parse::<BankLayout>().is_err());
+    }
+
+    #[test]
+    fn test_split_partition_path() {
+        assert_eq!(split_partition_path("/dev/sda2"), Some(("/dev/sda", 2)));
+        assert_eq!(split_partition_path("/dev/mmcblk0p12"), Some(("/dev/mmcblk0", 12)));
+        assert_eq!(split_partition_path("/dev/nvme0n1p3"), Some(("/dev/nvme0n1", 3)));
+        assert_eq!(split_partition_path("/dev/sda"), None);
+        assert_eq!(split_partition_path("42"), None);
+    }
+
+    #[test]
+    fn test_resolve_root_ids() {
+        // a fake /dev with an MBR disk "sda", a GPT disk "sdb", and udev symlinks
+        let dev = tempfile::tempdir().unwrap();
+        let devpath = |name: &str| dev.path().join(name).to_string_lossy().into_owned();
+        fs::write(dev.path().join("sda"), parttable::tests::mbr_image(0x6c586e13)).unwrap();
+        fs::write(dev.path().join("sdb"), parttable::tests::gpt_image(3)).unwrap();
+        for name in ["sda2", "sda3", "sdb2", "sdb3"].iter() {
+            fs::write(dev.path().join(name), b"").unwrap();
+        }
+        fs::create_dir_all(dev.path().join("disk/by-label")).unwrap();
+        std::os::unix::fs::symlink("../../sda2", dev.path().join("disk/by-label/rootfs_a"))
+            .unwrap();
+
+        let layout = |disk: &str| BankLayout {
+            boot_device: devpath(&format!("{}1", disk)),
+            boot_dir: PathBuf::from(DEFAULT_BOOT_DIR),
+            rootfs: vec![
+                RootfsBank::new(&devpath(&format!("{}2", disk))),
+                RootfsBank::new(&devpath(&format!("{}3", disk))),
+            ],
+            state_file: None,
+            tryboot: false,
+            mounted: MountPolicy::default(),
+            dev_disk_dir: dev.path().join("disk"),
+        };
+
+        // MBR PARTUUIDs, written back in the same style
+        let mbr = layout("sda");
+        let cmdline = "root=PARTUUID=6C586E13-02 rootwait";
+        let bank = mbr.inactive_rootfs(cmdline).unwrap();
+        assert_eq!(bank.device, devpath("sda3"));
+        assert_eq!(mbr.root_spec(bank, cmdline), "PARTUUID=6c586e13-03");
+        assert_eq!(mbr.inactive_rootfs("root=PARTUUID=6c586e13-05"), None);
+
+        // udev label, sda3 doesn't have one so fall back to the device path
+        let cmdline = "root=LABEL=rootfs_a";
+        let bank = mbr.inactive_rootfs(cmdline).unwrap();
+        assert_eq!(bank.device, devpath("sda3"));
+        assert_eq!(mbr.root_spec(bank, cmdline), devpath("sda3"));
+        assert_eq!(mbr.root_spec(&mbr.rootfs[0], cmdline), "LABEL=rootfs_a");
+
+        // GPT PARTUUID and PARTLABEL
+        let gpt = layout("sdb");
+        let cmdline = "root=PARTUUID=03030303-0303-0303-0303-030303030303";
+        let bank = gpt.inactive_rootfs(cmdline).unwrap();
+        assert_eq!(bank.device, devpath("sdb2"));
+        assert_eq!(gpt.root_spec(bank, cmdline), "PARTUUID=02020202-0202-0202-0202-020202020202");
+        let bank = gpt.inactive_rootfs("root=PARTLABEL=part2").unwrap();
+        assert_eq!(bank.device, devpath("sdb3"));
+        assert_eq!(gpt.root_spec(bank, "root=PARTLABEL=part2"), "PARTLABEL=part3");
+
+        // config file identifiers override the partition table
+        let mut gpt = gpt;
+        gpt.rootfs[1].partuuid = Some(String::from("6c586e13-03"));
+        assert_eq!(
+            gpt.inactive_rootfs("root=PARTUUID=6c586e13-03").unwrap().device,
+            devpath("sdb2")
+        );
     }
 
     #[test]
@@ -150,4 +708,50 @@ mod tests {
         );
         assert_eq!(update_rootfs("", "/dev/mmcblk0p2", true), "root=/dev/mmcblk0p2 rw");
     }
+
+    #[test]
+    fn test_switch_rootfs() {
+        let boot = tempfile::tempdir().unwrap();
+        let path = boot.path().join("cmdline.txt");
+
+        // missing cmdline.txt is an error, don't create one from scratch
+        assert!(switch_rootfs(boot.path(), "/dev/mmcblk0p3", false).is_err());
+        assert!(!path.exists());
+
+        fs::write(&path, "console=tty0 root=/dev/mmcblk0p2 rw rootwait\n").unwrap();
+        switch_rootfs(boot.path(), "/dev/mmcblk0p3", false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "console=tty0 root=/dev/mmcblk0p3 ro rootwait\n"
+        );
+
+        // no temp files left behind
+        assert_eq!(fs::read_dir(boot.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_tryboot() {
+        let boot = tempfile::tempdir().unwrap();
+        let cmdline = "console=tty0 root=/dev/mmcblk0p2 ro rootwait\n";
+        fs::write(boot.path().join("cmdline.txt"), cmdline).unwrap();
+        fs::write(boot.path().join("config.txt"), "dtparam=audio=on\n[pi4]\narm_boost=1").unwrap();
+
+        stage_tryboot(boot.path(), "/dev/mmcblk0p3", true).unwrap();
+        assert_eq!(
+            fs::read_to_string(boot.path().join(TRYBOOT_CMDLINE)).unwrap(),
+            "console=tty0 root=/dev/mmcblk0p3 rw rootwait\n"
+        );
+        assert_eq!(
+            fs::read_to_string(boot.path().join(TRYBOOT_CONFIG)).unwrap(),
+            "dtparam=audio=on\n[pi4]\narm_boost=1\n\n# added by swdl\n[all]\ncmdline=cmdline-tryboot.txt\n"
+        );
+        // the normal boot files aren't touched
+        assert_eq!(fs::read_to_string(boot.path().join("cmdline.txt")).unwrap(), cmdline);
+        assert_eq!(fs::read_dir(boot.path()).unwrap().count(), 4);
+
+        clear_tryboot(boot.path()).unwrap();
+        assert_eq!(fs::read_dir(boot.path()).unwrap().count(), 2);
+        // clearing again is fine
+        clear_tryboot(boot.path()).unwrap();
+    }
 }
@@ -0,0 +1,437 @@
+/*!
+ * swdl: Raspberry Pi firmware update engine.
+ * built-in HTTP(S) download client
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ */
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use yall::log_macros::*;
+
+/// Timeout for establishing a connection to the server
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Timeout for each read from the server, a stalled transfer is resumed after this long
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum number of redirects to follow
+const HTTP_MAX_REDIRECTS: u32 = 10;
+
+/// Give up resuming after this many attempts in a row which don't download any data
+const HTTP_MAX_RETRIES: u32 = 5;
+
+/// Delay before the first resume attempt, multiplied by the attempt number for later retries
+const HTTP_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Environment variable holding a bearer token to send with every request
+pub const BEARER_TOKEN_ENV: &str = "SWDL_BEARER_TOKEN";
+
+/// Check whether path looks like a URL that HttpInput can download
+pub fn is_http_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Credentials for an HTTP request
+#[derive(Clone, Eq, PartialEq)]
+enum Auth {
+    None,
+    Basic { login: String, password: String },
+    Bearer(String),
+}
+
+// don't leak passwords into debug logs
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Auth::None => "None",
+            Auth::Basic { .. } => "Basic",
+            Auth::Bearer(_) => "Bearer",
+        })
+    }
+}
+
+/**
+ * Look up the login and password for host in the contents of a .netrc file. A "default"
+ * entry matches any host, but only if no "machine" entry matched first.
+ */
+fn netrc_lookup(netrc: &str, host: &str) -> Option<(String, String)> {
+    let mut tokens = netrc.split_whitespace();
+    let mut matched = false;
+    let mut login = None;
+    let mut password = None;
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" => {
+                if matched {
+                    break;
+                }
+                matched = token == "default" || tokens.next() == Some(host);
+            }
+            "login" if matched => login = tokens.next().map(String::from),
+            "password" if matched => password = tokens.next().map(String::from),
+            "login" | "password" | "account" => {
+                tokens.next();
+            }
+            "macdef" => {
+                // macro definitions run to the next blank line, which split_whitespace can't
+                // see. They're rare enough in practice that we just stop parsing here.
+                break;
+            }
+            _ => (),
+        }
+    }
+    if matched {
+        Some((login.unwrap_or_default(), password.unwrap_or_default()))
+    } else {
+        None
+    }
+}
+
+/// Find credentials for host, from $SWDL_BEARER_TOKEN or the $NETRC or ~/.netrc file
+fn find_auth(host: &str) -> Auth {
+    if let Ok(token) = env::var(BEARER_TOKEN_ENV) {
+        debug!("using bearer token from ${}", BEARER_TOKEN_ENV);
+        return Auth::Bearer(token);
+    }
+
+    let path = match env::var_os("NETRC") {
+        Some(path) => PathBuf::from(path),
+        None => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".netrc"),
+            None => return Auth::None,
+        },
+    };
+    match fs::read_to_string(&path).map(|netrc| netrc_lookup(&netrc, host)) {
+        Ok(Some((login, password))) => {
+            debug!("using credentials for {} from {}", host, path.display());
+            Auth::Basic { login, password }
+        }
+        Ok(None) => Auth::None,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("failed to read {}: {}", path.display(), e);
+            }
+            Auth::None
+        }
+    }
+}
+
+/**
+ * Drop the credentials for url unless it's HTTPS, plain HTTP would send them to anyone on the
+ * network. The image itself is protected by its signature, but credentials aren't.
+ */
+fn https_only(url: &str, auth: Auth) -> Auth {
+    if auth != Auth::None && !url.starts_with("https://") {
+        warn!("not sending credentials over plain HTTP to '{}'", url);
+        return Auth::None;
+    }
+    auth
+}
+
+/**
+ * Parse the start offset and total length out of a Content-Range header value,
+ * e.g. "bytes 100-199/200" is (100, Some(200)).
+ */
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let range = value.trim().strip_prefix("bytes ")?;
+    let dash = range.find('-')?;
+    let slash = range.find('/')?;
+    let start = range[..dash].trim().parse().ok()?;
+    let total = range[(slash + 1)..].trim().parse().ok();
+    Some((start, total))
+}
+
+/**
+ * A file being downloaded over HTTP or HTTPS. If the connection drops or stalls partway
+ * through, the download is transparently resumed with a Range request starting at the
+ * next byte the caller hasn't seen yet, so any hash the caller computes stays valid.
+ */
+pub struct HttpInput {
+    url: String,
+    agent: ureq::Agent,
+    auth: Auth,
+    body: Box<dyn Read + Send>,
+    // number of bytes returned by read() so far, where a resumed download picks up from
+    offset: u64,
+    // total size of the file, if the server told us
+    length: Option<u64>,
+    // number of resume attempts since the last successful read
+    retries: u32,
+}
+
+impl fmt::Debug for HttpInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpInput")
+            .field("url", &self.url)
+            .field("auth", &self.auth)
+            .field("offset", &self.offset)
+            .field("length", &self.length)
+            .finish()
+    }
+}
+
+impl HttpInput {
+    /**
+     * Start downloading url. Returns an error if the server can't be reached or responds
+     * with an HTTP error status.
+     */
+    pub fn new(url: &str) -> Result<Self> {
+        let agent = ureq::Agent::new();
+        let host =
+            agent.get(url).get_host().map_err(|e| anyhow!("invalid URL '{}': {}", url, e))?;
+        let auth = https_only(url, find_auth(&host));
+        let mut input = HttpInput {
+            url: url.to_string(),
+            agent,
+            auth,
+            body: Box::new(io::empty()),
+            offset: 0,
+            length: None,
+            retries: 0,
+        };
+        input.request()?;
+        Ok(input)
+    }
+
+    /// URL being downloaded
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Total size of the file being downloaded, if the server sent a Content-Length
+    pub fn len(&self) -> Option<u64> {
+        self.length
+    }
+
+    /**
+     * Send a GET request for the file, starting at self.offset. On success, the response
+     * body replaces self.body.
+     */
+    fn request(&mut self) -> Result<()> {
+        let mut req = self.agent.get(&self.url);
+        req.timeout_connect(HTTP_CONNECT_TIMEOUT.as_millis() as u64)
+            .timeout_read(HTTP_READ_TIMEOUT.as_millis() as u64)
+            .redirects(HTTP_MAX_REDIRECTS);
+        match &self.auth {
+            Auth::None => (),
+            Auth::Basic { login, password } => {
+                req.auth(login, password);
+            }
+            Auth::Bearer(token) => {
+                req.auth_kind("Bearer", token);
+            }
+        }
+        if self.offset != 0 {
+            req.set("Range", &format!("bytes={}-", self.offset));
+        }
+
+        let resp = req.call();
+        if let Some(err) = resp.synthetic_error() {
+            return Err(anyhow!("failed to download '{}': {}", self.url, err));
+        }
+        if resp.error() {
+            return Err(anyhow!(
+                "failed to download '{}': HTTP {} {}",
+                self.url,
+                resp.status(),
+                resp.status_text()
+            ));
+        }
+
+        let content_length = resp.header("Content-Length").and_then(|l| l.trim().parse().ok());
+        if self.offset == 0 {
+            self.length = content_length;
+        } else {
+            // make sure the server actually sent the part we asked for, rather than
+            // starting over from the beginning
+            let range = resp.header("Content-Range").and_then(parse_content_range);
+            match range {
+                Some((start, total)) if resp.status() == 206 && start == self.offset => {
+                    if total.is_some() && self.length.is_some() && total != self.length {
+                        return Err(anyhow!("'{}' changed size while downloading", self.url));
+                    }
+                }
+                _ => return Err(anyhow!("server doesn't support resuming '{}'", self.url)),
+            }
+        }
+        debug!(
+            "HTTP {} for {} at offset {}, length {:?}",
+            resp.status(),
+            self.url,
+            self.offset,
+            content_length
+        );
+
+        self.body = Box::new(resp.into_reader());
+        Ok(())
+    }
+
+    /// Wait a bit, then try to reconnect and resume the download from self.offset
+    fn resume(&mut self, reason: &str) -> io::Result<()> {
+        loop {
+            if self.retries >= HTTP_MAX_RETRIES {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("download failed after {} retries: {}", self.retries, reason),
+                ));
+            }
+            self.retries += 1;
+            warn!(
+                "download interrupted ({}), resuming at offset {} (attempt {}/{})",
+                reason, self.offset, self.retries, HTTP_MAX_RETRIES
+            );
+            thread::sleep(HTTP_RETRY_DELAY * self.retries);
+
+            match self.request() {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+    }
+}
+
+impl Read for HttpInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.body.read(buf) {
+                Ok(0) => match self.length {
+                    Some(len) if self.offset < len => {
+                        let reason =
+                            format!("connection closed after {}/{} bytes", self.offset, len);
+                        self.resume(&reason)?;
+                    }
+                    _ => return Ok(0),
+                },
+                Ok(count) => {
+                    self.offset += count as u64;
+                    self.retries = 0;
+                    return Ok(count);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => self.resume(&e.to_string())?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /**
+     * Serve data over HTTP on localhost, one response per element of conns. Each element is
+     * how many bytes to send before dropping the connection, or None to send everything.
+     * Range requests are honored. Returns the URL and the ranges which were requested.
+     */
+    fn serve(data: Vec<u8>, conns: Vec<Option<usize>>) -> (String, thread::JoinHandle<Vec<u64>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/image.nimg", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut ranges = Vec::new();
+            for limit in conns {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut start = 0u64;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    let line = line.to_ascii_lowercase();
+                    if let Some(range) = line.strip_prefix("range: bytes=") {
+                        start = range.trim().trim_end_matches('-').parse().unwrap();
+                    }
+                }
+                ranges.push(start);
+
+                let body = &data[(start as usize)..];
+                if start == 0 {
+                    write!(stream, "HTTP/1.1 200 OK\r\n").unwrap();
+                } else {
+                    write!(stream, "HTTP/1.1 206 Partial Content\r\n").unwrap();
+                    let end = data.len() - 1;
+                    write!(stream, "Content-Range: bytes {}-{}/{}\r\n", start, end, data.len())
+                        .unwrap();
+                }
+                write!(stream, "Content-Length: {}\r\nConnection: close\r\n\r\n", body.len())
+                    .unwrap();
+                let _ = stream.write_all(&body[..limit.unwrap_or(body.len()).min(body.len())]);
+            }
+            ranges
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_resume() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let (url, server) = serve(data.clone(), vec![Some(70_000), Some(50_000), None]);
+
+        let mut input = HttpInput::new(&url).unwrap();
+        assert_eq!(input.len(), Some(200_000));
+        let mut out = Vec::new();
+        input.read_to_end(&mut out).unwrap();
+        assert!(out == data, "downloaded data doesn't match");
+
+        let ranges = server.join().unwrap();
+        assert_eq!(ranges, [0, 70_000, 120_000]);
+    }
+
+    #[test]
+    fn test_http_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/missing.nimg", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").unwrap();
+        });
+
+        let err = HttpInput::new(&url).unwrap_err();
+        assert_eq!(err.to_string(), format!("failed to download '{}': HTTP 404 Not Found", url));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_netrc_lookup() {
+        let netrc = "machine updates.example.com login pi password hunter2\n\
+                     machine other.example.com\n  login other\n  password secret\n\
+                     default login anonymous password guest\n";
+        let creds = |login: &str, password: &str| Some((login.to_string(), password.to_string()));
+        assert_eq!(netrc_lookup(netrc, "updates.example.com"), creds("pi", "hunter2"));
+        assert_eq!(netrc_lookup(netrc, "other.example.com"), creds("other", "secret"));
+        assert_eq!(netrc_lookup(netrc, "example.org"), creds("anonymous", "guest"));
+        assert_eq!(netrc_lookup("machine a login b", "c"), None);
+    }
+
+    #[test]
+    fn test_https_only() {
+        let token = Auth::Bearer(String::from("token"));
+        assert_eq!(https_only("https://example.com/rpi.img", token.clone()), token);
+        assert_eq!(https_only("http://example.com/rpi.img", token), Auth::None);
+        assert_eq!(https_only("http://example.com/rpi.img", Auth::None), Auth::None);
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("bytes 100-199/200"), Some((100, Some(200))));
+        assert_eq!(parse_content_range("bytes 100-199/*"), Some((100, None)));
+        assert_eq!(parse_content_range("items 1-2/3"), None);
+    }
+}
@@ -9,31 +9,27 @@
 use std::fmt;
 use std::fs::File;
 use std::io::{self, BufReader, Read};
-use std::os::unix::process::ExitStatusExt;
-use std::process::{Child, Command, Stdio};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use yall::log_macros::*;
 
+use crate::http::{is_http_url, HttpInput};
+
 #[derive(Debug)]
 pub struct FileInfo {
     // path as a string for easier printing. Technically should be a PathBuf
     path: String,
     // bufreader of the open file object
     file: BufReader<File>,
-}
-
-#[derive(Debug)]
-pub struct CurlInfo {
-    url: String,
-    child: Child,
+    // size of the file
+    len: u64,
 }
 
 #[derive(Debug)]
 pub enum Input {
     Stdin(BufReader<io::Stdin>),
     File(FileInfo),
-    Curl(CurlInfo),
+    Http(Box<HttpInput>),
 }
 
 impl Input {
@@ -44,26 +40,37 @@ impl Input {
             return Ok(Input::Stdin(BufReader::new(io::stdin())));
         }
 
-        // try to open path as a local file
-        match File::open(path) {
-            // sucess
-            Ok(file) => {
-                debug!("opened {} as a local file", path);
-                Ok(Input::File(FileInfo { path: path.to_string(), file: BufReader::new(file) }))
-            }
+        if is_http_url(path) {
+            debug!("downloading {}", path);
+            return Ok(Input::Http(Box::new(HttpInput::new(path)?)));
+        }
+
+        let file = File::open(path).with_context(|| format!("failed to open '{}'", path))?;
+        let len = file.metadata().with_context(|| format!("failed to stat '{}'", path))?.len();
+        debug!("opened {} as a local file", path);
+        Ok(Input::File(FileInfo { path: path.to_string(), file: BufReader::new(file), len }))
+    }
 
-            // couldn't open as a file, do it as a piped curl command
-            Err(_) => {
-                let child = Command::new("curl")
-                    .arg("-sSLf")
-                    .arg("--netrc")
-                    .arg("--")
-                    .arg(path)
-                    .stdout(Stdio::piped())
-                    .spawn()?;
-                debug!("downloading {} with curl", path);
-                Ok(Input::Curl(CurlInfo { url: path.to_string(), child }))
-            }
+    /**
+     * Total size of the input in bytes, if it's known ahead of time. For HTTP downloads
+     * this comes from the server's Content-Length.
+     */
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Input::Stdin(_) => None,
+            Input::File(info) => Some(info.len),
+            Input::Http(http) => http.len(),
+        }
+    }
+
+    /**
+     * Path and open file of a local file input, which can be read again. None for streamed
+     * inputs that can only be read once.
+     */
+    pub fn local_file(&self) -> Option<(&str, &File)> {
+        match self {
+            Input::File(info) => Some((&info.path, info.file.get_ref())),
+            Input::Stdin(_) | Input::Http(_) => None,
         }
     }
 }
@@ -73,7 +80,7 @@ impl fmt::Display for Input {
         f.write_str(match self {
             Input::Stdin(_) => "[standard input]",
             Input::File(info) => &info.path,
-            Input::Curl(info) => &info.url,
+            Input::Http(http) => http.url(),
         })
     }
 }
@@ -85,37 +92,8 @@ impl Read for Input {
             Input::Stdin(r) => r.read(buf),
             Input::File(info) => info.file.read(buf),
 
-            // curl pipe is trickier
-            Input::Curl(info) => {
-                // try the read, immediately returning any read error
-                let count = info.child.stdout.as_mut().unwrap().read(buf)?;
-                if count != 0 {
-                    // we read some bytes
-                    return Ok(count);
-                }
-
-                // we read nothing, which means curl is done, check its return status.
-                // wait() could return an error but that shouldn't happen
-                let status = info.child.wait().expect("failed to wait for curl process");
-                if status.success() {
-                    Ok(0)
-                } else if let Some(code) = status.code() {
-                    // normal non-successful exit
-                    Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("curl process exited with status {}", code),
-                    ))
-                } else if let Some(sig) = status.signal() {
-                    // killed by a signal
-                    Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("curl process killed with signal {}", sig),
-                    ))
-                } else {
-                    // should never get here
-                    panic!("curl process exited in an unknown fashion!")
-                }
-            }
+            // HTTP might have to reconnect and resume
+            Input::Http(http) => http.read(buf),
         }
     }
 }
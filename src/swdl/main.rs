@@ -6,28 +6,128 @@
  * SPDX-License-Identifier: GPL-3.0-or-later
  */
 
+mod boottar;
 mod flashbanks;
+mod http;
 mod input;
+mod mounts;
+mod parttable;
 mod program;
+mod safety;
+mod state;
 
-use std::io::Read;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
 use anyhow::{anyhow, Context, Result};
-use clap::{crate_version, App, AppSettings, Arg};
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
 use yall::{log_macros::*, Logger};
 
 use nimage::format::*;
+use nimage::reader::{ImageReader, DEFAULT_MAX_PADDING};
+use nimage::sign::{check_signable, verify_header, TrustedKey, SIGNATURE_EXT};
+use nimage::util::human_size;
 
+use flashbanks::{
+    clear_tryboot, new_root_spec, read_cmdline, running_root_spec, stage_tryboot, switch_rootfs,
+    write_boot_file, BankLayout, DEFAULT_BOOT_DIR, DEFAULT_CONFIG_PATH, DEFAULT_STATE_FILE_NAME,
+};
+use http::BEARER_TOKEN_ENV;
 use input::Input;
 use program::program_part;
+use state::{UpdateState, UpdateStatus};
 
-#[allow(clippy::comparison_chain)] // suppress lint on the "if part.offset < current_offset"
-fn do_swdl(url: &str) -> Result<()> {
+/// Directory of public keys which are trusted to sign images
+const DEFAULT_KEY_DIR: &str = "/etc/swdl/keys";
+
+/// Limit on the size of a signature file, which is really only 129 bytes
+const SIGNATURE_MAX_LEN: u64 = 4096;
+
+/// Detached signature to check before programming anything
+struct SignatureCheck {
+    /// file path or URL of the signature
+    path: String,
+    /// keys which are trusted to sign images
+    keys: Vec<TrustedKey>,
+}
+
+impl SignatureCheck {
+    /**
+     * Set up signature checking for the image at url, using the signature at sig_path
+     * (default: url with ".sig" appended) and the keys in key_dir.
+     */
+    fn new(url: &str, sig_path: Option<&str>, key_dir: &Path) -> Result<Self> {
+        let path = match sig_path {
+            Some(path) => path.to_string(),
+            None if url != "-" => format!("{}{}", url, SIGNATURE_EXT),
+            None => return Err(anyhow!("a signature path is required when reading stdin")),
+        };
+        let keys = TrustedKey::load_dir(key_dir)?;
+        if keys.is_empty() {
+            return Err(anyhow!("no trusted keys found in '{}'", key_dir.display()));
+        }
+        Ok(SignatureCheck { path, keys })
+    }
+
+    /// Download the signature and verify it against the raw image header
+    fn verify(&self, header: &[u8]) -> Result<()> {
+        let mut signature = String::new();
+        Input::new(&self.path)?
+            .take(SIGNATURE_MAX_LEN)
+            .read_to_string(&mut signature)
+            .with_context(|| format!("failed to read signature '{}'", self.path))?;
+        let key = verify_header(header, &signature, &self.keys)?;
+        info!("Image signature verified with {}", key.path.display());
+        Ok(())
+    }
+}
+
+/**
+ * Read a local image file and verify the hash of every part, without programming anything.
+ * The file is checked against the same raw header that the signature was checked with.
+ *
+ * This reads through a duplicate of the already open file descriptor rather than opening the
+ * path again, so it's the same file that gets programmed. They share a file offset, which is
+ * put back afterwards.
+ */
+fn preflight(path: &str, file: &File, raw_header: &[u8], max_padding: u64) -> Result<()> {
+    info!("Verifying every part of {} before programming", path);
+    let mut file = file.try_clone().with_context(|| format!("failed to reopen '{}'", path))?;
+    let pos = file.stream_position()?;
+    file.seek(SeekFrom::Start(raw_header.len() as u64))?;
+    let mut image = ImageReader::with_raw_header(BufReader::new(&file), raw_header.to_vec())?;
+    image.set_max_padding(max_padding);
+    while let Some(part) = image.next_part()? {
+        let index = part.index();
+        part.finish().with_context(|| format!("part {} failed verification", index))?;
+    }
+    file.seek(SeekFrom::Start(pos))?;
+    info!("All parts verified");
+    Ok(())
+}
+
+fn do_swdl(
+    url: &str,
+    layout: &BankLayout,
+    sigcheck: Option<&SignatureCheck>,
+    max_padding: u64,
+    status: &mut UpdateStatus,
+) -> Result<()> {
     let mut input = Input::new(url)?;
-    let mut header = [0u8; NIMG_HDR_SIZE];
-    input.read_exact(&mut header).context("failed to read image header")?;
-    let header = ImageHeader::from_bytes(&header).context("failed to parse image header")?;
+    let raw_header = ImageHeader::read_raw(&mut input).context("failed to read image header")?;
+    // nothing in the header can be trusted until the signature is checked
+    if let Some(sigcheck) = sigcheck {
+        sigcheck.verify(&raw_header)?;
+    }
+    let mut image = ImageReader::with_raw_header(input, raw_header.clone())
+        .context("failed to parse image header")?;
+    image.set_max_padding(max_padding);
+    let header = image.header().clone();
+    if sigcheck.is_some() {
+        check_signable(&header)?;
+    }
     info!("Image name is {}", if header.name.is_empty() { "empty" } else { &header.name });
 
     if header.parts.is_empty() {
@@ -35,24 +135,218 @@ fn do_swdl(url: &str) -> Result<()> {
         return Ok(());
     }
 
-    let mut current_offset = 0u64;
-    for (i, part) in header.parts.iter().enumerate() {
-        if part.offset < current_offset {
-            return Err(anyhow!("Part {} offset {} is out of order", i, part.offset));
-        } else if part.offset > current_offset {
-            let pad_bytes = part.offset - current_offset;
-            let mut padding = vec![0u8; pad_bytes as usize];
-            input
-                .read_exact(&mut padding)
-                .with_context(|| format!("failed to read padding before part {}", i))?;
-            current_offset += pad_bytes;
-            debug!("read {} bytes of padding", pad_bytes);
+    // make sure the input has every part, rather than finding out partway through programming
+    let input = image.get_ref();
+    let image_size = (header.header_size() as u64)
+        .checked_add(header.data_size())
+        .ok_or_else(|| anyhow!("image header has parts past the end of any file"))?;
+    if let Some(len) = input.len() {
+        if len < image_size {
+            return Err(anyhow!(
+                "{} is truncated: it's {} bytes but the image header needs {}",
+                input,
+                len,
+                image_size
+            ));
+        }
+    }
+
+    // Nothing is written to the shared boot partition until every part is verified, so rootfs
+    // parts go straight to the inactive bank and boot parts are staged until the end of the
+    // image. A local file can be read twice, so hash the whole thing first too, which catches a
+    // bad image before the inactive bank is overwritten.
+    if let Some((path, file)) = image.get_ref().local_file() {
+        preflight(path, file, &raw_header, max_padding)?;
+    }
+
+    // If the image has multiple rootfs parts, the last one wins.
+    let rootfs_part = header
+        .parts
+        .iter()
+        .rev()
+        .find(|p| p.ptype == PartType::Rootfs || p.ptype == PartType::RootfsRw);
+
+    // worked out from the old status, before any of it is replaced below
+    let previous_cmdline = match rootfs_part {
+        Some(_) => {
+            Some(status.rollback_cmdline(&read_cmdline(&layout.boot_dir)?, running_root_spec)?)
+        }
+        None => None,
+    };
+
+    if status.state.is_interrupted() {
+        warn!(
+            "replacing the interrupted update of {}",
+            status.image.as_deref().unwrap_or("an unknown image")
+        );
+    }
+    status.image = Some(url.to_string());
+    status.signature = sigcheck.map(|s| s.path.clone());
+    status.image_name = Some(header.name.clone());
+    status.rootfs = match rootfs_part {
+        Some(_) => Some(new_root_spec(layout)?),
+        None => None,
+    };
+    status.rootfs_rw = matches!(rootfs_part, Some(p) if p.ptype == PartType::RootfsRw);
+    status.tryboot = false;
+    status.previous_cmdline = previous_cmdline;
+    status.set_state(&layout.state_file(), UpdateState::Writing)?;
+    // a leftover tryboot setup could point at the bank that's about to be overwritten
+    clear_tryboot(&layout.boot_dir)?;
+
+    let mut staged = Vec::new();
+    while let Some(part) = image.next_part()? {
+        if let Some(staged_part) = program_part(part, layout)? {
+            staged.push(staged_part);
         }
+    }
 
-        program_part(&mut input, part)?;
-        current_offset += part.size;
+    if !staged.is_empty() {
+        info!("All parts verified, committing boot partition changes");
+        for staged_part in staged {
+            let written = staged_part.commit()?;
+            info!("Wrote: {}", human_size(written));
+        }
     }
 
+    status.set_state(&layout.state_file(), UpdateState::Written)?;
+    switch_bank(layout, status)
+}
+
+/**
+ * Every part is programmed and verified, now it's safe to point the bootloader at the new
+ * rootfs. This is the last step of an update.
+ */
+fn switch_bank(layout: &BankLayout, status: &mut UpdateStatus) -> Result<()> {
+    if let Some(new_rootfs) = status.rootfs.clone() {
+        if layout.tryboot {
+            stage_tryboot(&layout.boot_dir, &new_rootfs, status.rootfs_rw)
+                .context("failed to set up tryboot")?;
+            status.tryboot = true;
+        } else {
+            switch_rootfs(&layout.boot_dir, &new_rootfs, status.rootfs_rw)
+                .context("failed to switch active rootfs")?;
+        }
+    }
+    status.set_state(&layout.state_file(), UpdateState::PendingBoot)?;
+    if status.tryboot {
+        info!("Update complete, run \"reboot '0 tryboot'\" to try the new image once");
+        info!("After it boots, run 'swdl confirm' to keep it or 'swdl rollback' to discard it");
+    } else {
+        info!("Update complete, the new image will run after a reboot");
+    }
+    Ok(())
+}
+
+/**
+ * Mark the new image as good, once it's running. With tryboot, this is when the new rootfs
+ * becomes permanent.
+ */
+fn confirm(layout: &BankLayout, status: &mut UpdateStatus) -> Result<()> {
+    if status.state != UpdateState::PendingBoot {
+        return Err(anyhow!(
+            "no update is waiting to be confirmed, the update state is {}",
+            status.state
+        ));
+    }
+    if let Some(new_rootfs) = status.rootfs.clone() {
+        let running = running_root_spec()?;
+        if running != new_rootfs {
+            return Err(anyhow!(
+                "the new image isn't running, root is {} rather than {}",
+                running,
+                new_rootfs
+            ));
+        }
+        if status.tryboot {
+            switch_rootfs(&layout.boot_dir, &new_rootfs, status.rootfs_rw)
+                .context("failed to switch active rootfs")?;
+            clear_tryboot(&layout.boot_dir)?;
+        }
+    }
+    status.set_state(&layout.state_file(), UpdateState::Confirmed)?;
+    info!("Confirmed the update to {}", status.image_name.as_deref().unwrap_or("the new image"));
+    Ok(())
+}
+
+/**
+ * Go back to the rootfs that was running before the last update, by removing any tryboot
+ * setup and restoring the old cmdline.txt. This doesn't undo changes to the boot partition.
+ */
+fn rollback(layout: &BankLayout, status: &mut UpdateStatus) -> Result<()> {
+    if !matches!(status.state, UpdateState::PendingBoot | UpdateState::Confirmed) {
+        return Err(anyhow!("no update to roll back, the update state is {}", status.state));
+    }
+    let previous = status.previous_cmdline.clone().ok_or_else(|| {
+        anyhow!("the last update didn't switch the rootfs, there's nothing to roll back to")
+    })?;
+    clear_tryboot(&layout.boot_dir)?;
+    write_boot_file(&layout.boot_dir, "cmdline.txt", &previous)?;
+    status.set_state(&layout.state_file(), UpdateState::RolledBack)?;
+    info!("Rolled back, the previous image will run after a reboot");
+    Ok(())
+}
+
+/**
+ * Finish an interrupted update. If it was interrupted while writing, the image is programmed
+ * again from the start, otherwise only the rootfs switch is left to do.
+ */
+fn resume(args: &ArgMatches, layout: &BankLayout, status: &mut UpdateStatus) -> Result<()> {
+    match status.state {
+        UpdateState::Writing => {
+            let url = match status.image.clone() {
+                Some(url) if url == "-" => {
+                    return Err(anyhow!(
+                        "can't resume an update from standard input, run it again or abort it"
+                    ))
+                }
+                Some(url) => url,
+                None => return Err(anyhow!("the interrupted update didn't record its image")),
+            };
+            info!("Restarting the interrupted update of {}", url);
+            let sigcheck = signature_check(args, &url, status.signature.as_deref())?;
+            do_swdl(&url, layout, sigcheck.as_ref(), max_padding(args)?, status)
+        }
+        UpdateState::Written => {
+            info!("Every part is already written, finishing the update");
+            switch_bank(layout, status)
+        }
+        state => Err(anyhow!("no interrupted update to resume, the update state is {}", state)),
+    }
+}
+
+/**
+ * Give up on an interrupted update and go back to the idle state.
+ */
+fn abort(layout: &BankLayout, status: &mut UpdateStatus) -> Result<()> {
+    if !status.state.is_interrupted() {
+        return Err(anyhow!(
+            "no interrupted update to abort, the update state is {}",
+            status.state
+        ));
+    }
+    warn!(
+        "Aborting the update of {}, the inactive rootfs doesn't hold a usable image",
+        status.image.as_deref().unwrap_or("an unknown image")
+    );
+    if status.state == UpdateState::Writing {
+        warn!(
+            "The boot partition may have been partially updated, run a new update before rebooting"
+        );
+    }
+    *status = UpdateStatus { state: status.state, ..Default::default() };
+    status.set_state(&layout.state_file(), UpdateState::Idle)
+}
+
+fn print_status(status: &UpdateStatus, json: bool) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if json {
+        serde_json::to_writer_pretty(&mut stdout, status)?;
+        writeln!(stdout)?;
+    } else {
+        status.print_to(&mut stdout)?;
+    }
     Ok(())
 }
 
@@ -63,25 +357,175 @@ fn main() {
         .about("RPi Software Download")
         .max_term_width(100)
         .global_setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("debug")
                 .short("D")
                 .long("debug")
                 .help("Enable extra debug output")
         )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .value_name("FILE")
+                .help(&format!("Bank layout config file [default: {}, if it exists]", DEFAULT_CONFIG_PATH))
+        )
+        .arg(
+            Arg::with_name("boot_dir")
+                .short("b")
+                .long("boot-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .help(&format!("Mount point of the boot partition, overrides the bank layout \
+                                [default: {}]", DEFAULT_BOOT_DIR))
+        )
+        .arg(
+            Arg::with_name("key_dir")
+                .short("k")
+                .long("key-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .help(&format!("Directory of trusted public keys (*.pub) [default: {}]", DEFAULT_KEY_DIR))
+        )
+        .arg(
+            Arg::with_name("signature")
+                .short("s")
+                .long("signature")
+                .takes_value(true)
+                .value_name("FILE/URL")
+                .help("Detached image signature [default: IMAGE FILE/URL with .sig appended]")
+        )
+        .arg(
+            Arg::with_name("no_verify")
+                .long("no-verify")
+                .conflicts_with_all(&["key_dir", "signature"])
+                .help("Don't check the image signature. INSECURE, for development only!")
+        )
+        .arg(
+            Arg::with_name("max_padding")
+                .long("max-padding")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help(&format!("Largest gap allowed between parts in the image [default: {}]",
+                               DEFAULT_MAX_PADDING))
+        )
+        .arg(
+            Arg::with_name("state_file")
+                .long("state-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help(&format!("Where to save the update state, overrides the bank layout \
+                                [default: {} in the boot directory]",
+                               DEFAULT_STATE_FILE_NAME))
+        )
         .arg(
             Arg::with_name("url")
                 .required(true)
                 .value_name("IMAGE FILE/URL")
-                .help("Image to download. Can be a local file path, URL, or '-' for stdin"),
+                .help("Image to download. Can be a local file path, http(s) URL, or '-' for stdin"),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show the state of the current or most recent update")
+                .arg(
+                    Arg::with_name("json")
+                        .short("j")
+                        .long("json")
+                        .help("Print the state as JSON")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("resume")
+                .about("Finish an interrupted update, programming the image again if needed")
+        )
+        .subcommand(
+            SubCommand::with_name("abort")
+                .about("Abandon an interrupted update")
         )
+        .subcommand(
+            SubCommand::with_name("confirm")
+                .about("Mark the newly booted image as good")
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Go back to the rootfs that was running before the last update")
+        )
+        .after_help(format!("HTTP credentials are read from ~/.netrc (or $NETRC), unless a bearer \
+                             token is set in ${}. Credentials are only sent to HTTPS URLs.",
+                            BEARER_TOKEN_ENV).as_str())
         .get_matches();
 
     Logger::with_verbosity(3 + args.occurrences_of("debug")).init();
     debug!("debug logging enabled");
 
-    if let Err(err) = do_swdl(args.value_of("url").unwrap()) {
+    if let Err(err) = run(&args) {
         error!("{:#}", err);
         exit(1);
     }
 }
+
+/**
+ * Set up signature checking according to the command line options, for the image at url.
+ * default_sig is used if no signature path was given on the command line.
+ */
+fn signature_check(
+    args: &ArgMatches,
+    url: &str,
+    default_sig: Option<&str>,
+) -> Result<Option<SignatureCheck>> {
+    if args.is_present("no_verify") {
+        warn!("image signature verification is disabled!");
+        return Ok(None);
+    }
+    let key_dir = Path::new(args.value_of("key_dir").unwrap_or(DEFAULT_KEY_DIR));
+    let sig_path = args.value_of("signature").or(default_sig);
+    SignatureCheck::new(url, sig_path, key_dir).map(Some)
+}
+
+fn max_padding(args: &ArgMatches) -> Result<u64> {
+    match args.value_of("max_padding") {
+        Some(max) => max.parse().map_err(|_| anyhow!("invalid max padding '{}'", max)),
+        None => Ok(DEFAULT_MAX_PADDING),
+    }
+}
+
+fn run(args: &ArgMatches) -> Result<()> {
+    let mut layout = BankLayout::load(args.value_of("config").map(Path::new))?;
+    if let Some(dir) = args.value_of("boot_dir") {
+        layout.boot_dir = PathBuf::from(dir);
+    }
+    if let Some(path) = args.value_of("state_file") {
+        layout.state_file = Some(PathBuf::from(path));
+    }
+    // on x86, the default boot directory is a scratch directory which may not exist yet. Seed
+    // it with a cmdline.txt pointing at the fake running rootfs so switch_rootfs has one to edit.
+    #[cfg(target_arch = "x86_64")]
+    {
+        if layout.boot_dir == Path::new(DEFAULT_BOOT_DIR) {
+            std::fs::create_dir_all(DEFAULT_BOOT_DIR)
+                .with_context(|| format!("failed to create {}", DEFAULT_BOOT_DIR))?;
+            let cmdline = layout.boot_dir.join("cmdline.txt");
+            if !cmdline.exists() {
+                let contents = format!("root={} ro rootwait\n", flashbanks::running_root_spec()?);
+                std::fs::write(&cmdline, contents)
+                    .with_context(|| format!("failed to create {}", cmdline.display()))?;
+            }
+        }
+    }
+    let mut status = UpdateStatus::load(&layout.state_file())?;
+
+    match args.subcommand() {
+        ("status", Some(sub_args)) => print_status(&status, sub_args.is_present("json")),
+        ("resume", _) => resume(args, &layout, &mut status),
+        ("abort", _) => abort(&layout, &mut status),
+        ("confirm", _) => confirm(&layout, &mut status),
+        ("rollback", _) => rollback(&layout, &mut status),
+        _ => {
+            let url = args.value_of("url").unwrap();
+            let sigcheck = signature_check(args, url, None)?;
+            do_swdl(url, &layout, sigcheck.as_ref(), max_padding(args)?, &mut status)
+        }
+    }
+}
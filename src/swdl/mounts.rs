@@ -0,0 +1,394 @@
+/*!
+ * swdl: Raspberry Pi firmware update engine.
+ * handling of mounted filesystems on devices that are about to be programmed
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * Writing a raw image over a mounted filesystem corrupts it, because the kernel keeps writing
+ * back its cached metadata, so before a device is programmed swdl reads /proc/self/mountinfo
+ * to find out where it's mounted. Depending on the bank layout's MountPolicy, a mounted device
+ * is either refused, or unmounted for the write and mounted again afterwards. The device
+ * mounted as / is always refused.
+ */
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use yall::log_macros::*;
+
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// What to do when a device that's about to be programmed is mounted
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MountPolicy {
+    /// fail without writing anything
+    Refuse,
+    /// unmount the device, write it, then mount it again in the same place
+    Remount,
+}
+
+impl Default for MountPolicy {
+    fn default() -> Self {
+        MountPolicy::Remount
+    }
+}
+
+/// One mounted filesystem, parsed from a line of /proc/self/mountinfo
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MountInfo {
+    /// major and minor number of the mounted device
+    pub dev: (u32, u32),
+    /// directory within the filesystem which is the root of this mount, "/" unless it's a
+    /// bind mount
+    pub root: PathBuf,
+    /// where the filesystem is mounted
+    pub mount_point: PathBuf,
+    /// per-mount options, e.g. "rw,noatime"
+    pub mount_options: String,
+    /// filesystem type, e.g. "vfat"
+    pub fs_type: String,
+    /// mount source, usually the device path
+    pub source: String,
+    /// per-superblock options, e.g. "rw,fmask=0022"
+    pub super_options: String,
+}
+
+/**
+ * Split a Linux dev_t into its major and minor numbers, the same way as glibc's major() and
+ * minor() macros.
+ */
+pub fn split_dev(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xffff_f000);
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xffff_ff00);
+    (major as u32, minor as u32)
+}
+
+/// Decode the octal escapes (e.g. "\040" for a space) used in mountinfo paths
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let digits = std::str::from_utf8(&bytes[(i + 1)..(i + 4)]).unwrap_or("");
+            if let Ok(c) = u8::from_str_radix(digits, 8) {
+                out.push(c);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/**
+ * Parse a single line of mountinfo, e.g.
+ * `36 35 98:0 /mnt1 /mnt/parent rw,noatime master:1 - ext3 /dev/root rw,errors=continue`
+ */
+fn parse_mountinfo_line(line: &str) -> Option<MountInfo> {
+    let mut words = line.split(' ');
+    let _mount_id = words.next()?;
+    let _parent_id = words.next()?;
+    let mut dev = words.next()?.splitn(2, ':');
+    let dev = (dev.next()?.parse().ok()?, dev.next()?.parse().ok()?);
+    let root = PathBuf::from(unescape(words.next()?));
+    let mount_point = PathBuf::from(unescape(words.next()?));
+    let mount_options = words.next()?.to_string();
+    // skip the optional fields, which end with a lone "-"
+    words.find(|w| *w == "-")?;
+    let fs_type = words.next()?.to_string();
+    let source = unescape(words.next()?);
+    let super_options = words.next()?.to_string();
+    Some(MountInfo { dev, root, mount_point, mount_options, fs_type, source, super_options })
+}
+
+/**
+ * Parse the contents of a mountinfo file.
+ */
+pub fn parse_mountinfo(text: &str) -> Result<Vec<MountInfo>> {
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            parse_mountinfo_line(line).ok_or_else(|| anyhow!("invalid mountinfo line '{}'", line))
+        })
+        .collect()
+}
+
+/**
+ * Read the mount table of the current process.
+ */
+pub fn read_mountinfo() -> Result<Vec<MountInfo>> {
+    fs::read_to_string(MOUNTINFO_PATH)
+        .map_err(anyhow::Error::new)
+        .and_then(|s| parse_mountinfo(&s))
+        .with_context(|| format!("failed to read {}", MOUNTINFO_PATH))
+}
+
+/// Convert per-mount options to mount(2) flags
+fn mount_flags(options: &str) -> libc::c_ulong {
+    options
+        .split(',')
+        .map(|opt| match opt {
+            "ro" => libc::MS_RDONLY,
+            "nosuid" => libc::MS_NOSUID,
+            "nodev" => libc::MS_NODEV,
+            "noexec" => libc::MS_NOEXEC,
+            "sync" => libc::MS_SYNCHRONOUS,
+            "dirsync" => libc::MS_DIRSYNC,
+            "noatime" => libc::MS_NOATIME,
+            "nodiratime" => libc::MS_NODIRATIME,
+            "relatime" => libc::MS_RELATIME,
+            "strictatime" => libc::MS_STRICTATIME,
+            _ => 0,
+        })
+        .fold(0, |flags, flag| flags | flag)
+}
+
+/// Filesystem-specific options to pass to mount(2), without the generic ro/rw flag
+fn mount_data(super_options: &str) -> String {
+    super_options
+        .split(',')
+        .filter(|opt| *opt != "ro" && *opt != "rw")
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn cstring(s: &[u8]) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+impl MountInfo {
+    fn unmount(&self) -> io::Result<()> {
+        let target = cstring(self.mount_point.as_os_str().as_bytes())?;
+        // safe because target is a valid nul-terminated string
+        if unsafe { libc::umount2(target.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn mount(&self) -> io::Result<()> {
+        let source = cstring(self.source.as_bytes())?;
+        let target = cstring(self.mount_point.as_os_str().as_bytes())?;
+        let fs_type = cstring(self.fs_type.as_bytes())?;
+        let data = cstring(mount_data(&self.super_options).as_bytes())?;
+        // safe because every pointer is a valid nul-terminated string
+        let ret = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                fs_type.as_ptr(),
+                mount_flags(&self.mount_options),
+                data.as_ptr() as *const libc::c_void,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Find every mount of the block device with number dev. Returns an error if it's mounted
+ * as /, or if it's mounted and policy doesn't allow unmounting it.
+ */
+pub fn check_mounts(
+    mounts: &[MountInfo],
+    device: &Path,
+    dev: (u32, u32),
+    policy: MountPolicy,
+) -> Result<Vec<MountInfo>> {
+    let found: Vec<MountInfo> = mounts.iter().filter(|m| m.dev == dev).cloned().collect();
+    if found.is_empty() {
+        return Ok(found);
+    }
+    let mount_points =
+        found.iter().map(|m| m.mount_point.to_string_lossy()).collect::<Vec<_>>().join(", ");
+    if found.iter().any(|m| m.mount_point == Path::new("/")) {
+        return Err(anyhow!(
+            "refusing to write {}, it's the running root filesystem (mounted at {})",
+            device.display(),
+            mount_points
+        ));
+    }
+    match policy {
+        MountPolicy::Refuse => {
+            Err(anyhow!("refusing to write {}, it's mounted at {}", device.display(), mount_points))
+        }
+        MountPolicy::Remount if found.iter().any(|m| m.root != Path::new("/")) => Err(anyhow!(
+            "refusing to write {}, it has bind mounts which can't be restored ({})",
+            device.display(),
+            mount_points
+        )),
+        MountPolicy::Remount => Ok(found),
+    }
+}
+
+/**
+ * Filesystems which were unmounted so that their device could be written. Call remount() once
+ * the device is closed. If that doesn't happen, e.g. because the write failed, they're
+ * mounted again when this is dropped, and any errors are only logged.
+ */
+#[derive(Debug)]
+pub struct Unmounted {
+    mounts: Vec<MountInfo>,
+}
+
+/**
+ * Mount every filesystem in mounts. Every one is tried even if an earlier one fails, and the
+ * first error is returned.
+ */
+fn mount_all(mounts: &[MountInfo]) -> Result<()> {
+    let mut result = Ok(());
+    for mount in mounts.iter() {
+        let res = mount.mount().with_context(|| {
+            format!("failed to mount {} on {} again", mount.source, mount.mount_point.display())
+        });
+        match res {
+            Ok(()) => info!("Mounted {} on {}", mount.source, mount.mount_point.display()),
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(e) => error!("{:#}", e),
+        }
+    }
+    result
+}
+
+impl Unmounted {
+    /// Mount everything again, returning the first error
+    pub fn remount(mut self) -> Result<()> {
+        mount_all(&std::mem::take(&mut self.mounts))
+    }
+}
+
+impl Drop for Unmounted {
+    fn drop(&mut self) {
+        if let Err(e) = mount_all(&self.mounts) {
+            error!("{:#}", e);
+        }
+    }
+}
+
+/**
+ * Make sure that device can safely be programmed. If it's a block device with mounted
+ * filesystems, either refuse to write it or unmount them according to policy. Keep the
+ * returned value alive until the write is finished.
+ */
+pub fn release_device(device: &Path, policy: MountPolicy) -> Result<Unmounted> {
+    let mut unmounted = Unmounted { mounts: Vec::new() };
+    let meta =
+        fs::metadata(device).with_context(|| format!("failed to stat '{}'", device.display()))?;
+    if !meta.file_type().is_block_device() {
+        debug!("{} isn't a block device, not checking mounts", device.display());
+        return Ok(unmounted);
+    }
+
+    let found = check_mounts(&read_mountinfo()?, device, split_dev(meta.rdev()), policy)?;
+    // unmount in reverse order, so that anything mounted on top goes first
+    for mount in found.into_iter().rev() {
+        info!("Unmounting {} from {}", device.display(), mount.mount_point.display());
+        mount
+            .unmount()
+            .with_context(|| format!("failed to unmount '{}'", mount.mount_point.display()))?;
+        unmounted.mounts.insert(0, mount);
+    }
+    Ok(unmounted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 179:2 / / ro,relatime shared:1 - ext4 /dev/root ro
+23 22 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+31 22 179:1 / /boot rw,relatime shared:20 - vfat /dev/mmcblk0p1 rw,fmask=0022,dmask=0022,errors=remount-ro
+40 22 8:17 / /media/usb\\040stick rw,nosuid - vfat /dev/sdb1 rw
+41 22 8:17 /photos /srv/photos rw - vfat /dev/sdb1 rw
+";
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(MOUNTINFO).unwrap();
+        assert_eq!(mounts.len(), 5);
+        assert_eq!(
+            mounts[2],
+            MountInfo {
+                dev: (179, 1),
+                root: PathBuf::from("/"),
+                mount_point: PathBuf::from("/boot"),
+                mount_options: String::from("rw,relatime"),
+                fs_type: String::from("vfat"),
+                source: String::from("/dev/mmcblk0p1"),
+                super_options: String::from("rw,fmask=0022,dmask=0022,errors=remount-ro"),
+            }
+        );
+        assert_eq!(mounts[3].mount_point, Path::new("/media/usb stick"));
+        assert_eq!(mounts[4].root, Path::new("/photos"));
+
+        assert!(parse_mountinfo("22 1 179:2 / / ro,relatime shared:1").is_err());
+        assert!(parse_mountinfo("22 1 bogus / / ro - ext4 /dev/root ro").is_err());
+    }
+
+    #[test]
+    fn test_split_dev() {
+        assert_eq!(split_dev(0xb302), (179, 2));
+        assert_eq!(split_dev(0x0810), (8, 16));
+        // large minor numbers are split around the major
+        assert_eq!(split_dev(0x1234_5678_9abc_def0), (0x1234_5cde, 0x6789_abf0));
+    }
+
+    #[test]
+    fn test_mount_options() {
+        assert_eq!(
+            mount_flags("ro,nosuid,relatime"),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_RELATIME
+        );
+        assert_eq!(mount_flags("rw"), 0);
+        assert_eq!(mount_data("rw,fmask=0022,errors=remount-ro"), "fmask=0022,errors=remount-ro");
+        assert_eq!(mount_data("ro"), "");
+    }
+
+    #[test]
+    fn test_remount() {
+        // a filesystem that can't be mounted again is an error from remount(), rather than
+        // only being logged when Unmounted is dropped
+        let mounts =
+            parse_mountinfo("50 22 179:3 / /nonexistent/swdl-test rw - ext4 /dev/swdl-test rw")
+                .unwrap();
+        let err = Unmounted { mounts }.remount().unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/swdl-test"), "{}", err);
+
+        Unmounted { mounts: Vec::new() }.remount().unwrap();
+    }
+
+    #[test]
+    fn test_check_mounts() {
+        let mounts = parse_mountinfo(MOUNTINFO).unwrap();
+        let dev = Path::new("/dev/test");
+        // not mounted
+        assert!(check_mounts(&mounts, dev, (179, 3), MountPolicy::Refuse).unwrap().is_empty());
+
+        // the running rootfs is always refused
+        let err = check_mounts(&mounts, dev, (179, 2), MountPolicy::Remount).unwrap_err();
+        assert!(err.to_string().contains("running root filesystem"), "{}", err);
+
+        // /boot can be remounted if the policy allows
+        assert!(check_mounts(&mounts, dev, (179, 1), MountPolicy::Refuse).is_err());
+        let found = check_mounts(&mounts, dev, (179, 1), MountPolicy::Remount).unwrap();
+        assert_eq!(found, vec![mounts[2].clone()]);
+
+        // bind mounts can't be restored
+        assert!(check_mounts(&mounts, dev, (8, 17), MountPolicy::Remount).is_err());
+    }
+}
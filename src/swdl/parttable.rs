@@ -0,0 +1,294 @@
+/*!
+ * swdl: Raspberry Pi firmware update engine.
+ * MBR and GPT partition table parsing
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ */
+
+use std::convert::TryInto;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use nimage::util::ReadHelper;
+
+/// Logical sector size assumed for MBR partition tables
+const MBR_SECTOR_SIZE: u64 = 512;
+
+/// Sector sizes to try when looking for a GPT header at LBA 1
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+/// "EFI PART" signature at the start of the GPT header
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+/// MBR partition type of the protective MBR in front of a GPT
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// MBR partition types of extended partitions, which contain a chain of logical partitions
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// Upper limit on logical partitions, in case the EBR chain is corrupt and loops
+const MBR_MAX_LOGICAL: u32 = 128;
+
+/// Information about a single partition, as found in the partition table
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PartInfo {
+    /// partition number, as in the N in /dev/sdaN
+    pub number: u32,
+    /// partition UUID in the same format as the kernel's root=PARTUUID=
+    pub partuuid: String,
+    /// GPT partition name, None for MBR partitions
+    pub partlabel: Option<String>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_at<R: Read + Seek>(dev: &mut R, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    dev.seek(SeekFrom::Start(offset))?;
+    dev.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/**
+ * Format a GUID as stored on disk into the usual text form. The first three fields
+ * are little-endian and the last two are big-endian.
+ */
+fn format_guid(b: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        u32::from_le_bytes(b[0..4].try_into().unwrap()),
+        u16::from_le_bytes(b[4..6].try_into().unwrap()),
+        u16::from_le_bytes(b[6..8].try_into().unwrap()),
+        b[8],
+        b[9],
+        b[10],
+        b[11],
+        b[12],
+        b[13],
+        b[14],
+        b[15]
+    )
+}
+
+/// One primary or logical MBR partition entry
+struct MbrEntry {
+    ptype: u8,
+    start: u64,
+}
+
+/// Parse the 4 partition entries of an MBR or EBR sector
+fn mbr_entries(sector: &[u8]) -> Vec<MbrEntry> {
+    let mut reader = Cursor::new(sector);
+    (0..4)
+        .map(|i| {
+            reader.set_position(446 + 16 * i + 4);
+            let ptype = reader.read_byte().unwrap();
+            reader.skip(3);
+            let start = reader.read_u32_le().unwrap() as u64;
+            MbrEntry { ptype, start }
+        })
+        .collect()
+}
+
+/// Parse the GPT found at LBA 1, with the given sector size
+fn read_gpt<R: Read + Seek>(dev: &mut R, sector_size: u64) -> io::Result<Vec<PartInfo>> {
+    let header = read_at(dev, sector_size, 92)?;
+    let mut reader = Cursor::new(&header);
+    reader.set_position(72);
+    let entries_lba = reader.read_u64_le().unwrap();
+    let num_entries = reader.read_u32_le().unwrap();
+    let entry_size = reader.read_u32_le().unwrap() as usize;
+    // the spec allows any entry size of 128 * 2^n, but real tables always use 128. Limit the
+    // size and count so that a corrupt header can't make us allocate a huge buffer.
+    if !(128..=4096).contains(&entry_size) || !entry_size.is_power_of_two() || num_entries > 1024 {
+        return Err(invalid_data("bad GPT header"));
+    }
+
+    let offset = entries_lba.checked_mul(sector_size);
+    let size = entry_size.checked_mul(num_entries as usize);
+    let table = match (offset, size) {
+        (Some(offset), Some(size)) => read_at(dev, offset, size)?,
+        _ => return Err(invalid_data("bad GPT header")),
+    };
+    let mut parts = Vec::new();
+    for (i, entry) in table.chunks(entry_size).enumerate() {
+        // an all-zero partition type GUID means the slot is unused
+        if entry[..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let name: Vec<u16> = entry[56..128]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        parts.push(PartInfo {
+            number: i as u32 + 1,
+            partuuid: format_guid(&entry[16..32]),
+            partlabel: Some(String::from_utf16_lossy(&name)),
+        });
+    }
+    Ok(parts)
+}
+
+/**
+ * Read the partition table of a disk (or disk image file). Both MBR (including logical
+ * partitions) and GPT partition tables are supported.
+ */
+pub fn read_partitions<R: Read + Seek>(dev: &mut R) -> io::Result<Vec<PartInfo>> {
+    let mbr = read_at(dev, 0, MBR_SECTOR_SIZE as usize)?;
+    if mbr[510..] != [0x55, 0xaa] {
+        return Err(invalid_data("no partition table found"));
+    }
+
+    let entries = mbr_entries(&mbr);
+    if entries.iter().any(|e| e.ptype == MBR_TYPE_GPT_PROTECTIVE) {
+        for sector_size in GPT_SECTOR_SIZES.iter() {
+            if read_at(dev, *sector_size, GPT_SIGNATURE.len())? == GPT_SIGNATURE {
+                return read_gpt(dev, *sector_size);
+            }
+        }
+        return Err(invalid_data("protective MBR found, but no GPT header"));
+    }
+
+    // MBR PARTUUIDs are the disk signature and partition number, like "6c586e13-02"
+    let disk_sig = u32::from_le_bytes(mbr[440..444].try_into().unwrap());
+    let partinfo = |number| PartInfo {
+        number,
+        partuuid: format!("{:08x}-{:02x}", disk_sig, number),
+        partlabel: None,
+    };
+
+    let mut parts = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.ptype == 0 {
+            continue;
+        }
+        parts.push(partinfo(i as u32 + 1));
+
+        if MBR_TYPES_EXTENDED.contains(&entry.ptype) {
+            // Walk the chain of EBRs. Each EBR's first entry is a logical partition, its
+            // second entry points to the next EBR relative to the start of the extended
+            // partition. Logical partitions are numbered from 5.
+            let mut ebr_start = entry.start;
+            for number in 5..(5 + MBR_MAX_LOGICAL) {
+                let ebr = read_at(dev, ebr_start * MBR_SECTOR_SIZE, MBR_SECTOR_SIZE as usize)?;
+                let ebr_entries = mbr_entries(&ebr);
+                if ebr[510..] != [0x55, 0xaa] || ebr_entries[0].ptype == 0 {
+                    break;
+                }
+                parts.push(partinfo(number));
+                if ebr_entries[1].ptype == 0 {
+                    break;
+                }
+                ebr_start = entry.start + ebr_entries[1].start;
+            }
+        }
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Set an MBR partition entry in a disk image sector
+    fn set_mbr_entry(sector: &mut [u8], index: usize, ptype: u8, start: u32, size: u32) {
+        let off = 446 + 16 * index;
+        sector[off + 4] = ptype;
+        sector[(off + 8)..(off + 12)].copy_from_slice(&start.to_le_bytes());
+        sector[(off + 12)..(off + 16)].copy_from_slice(&size.to_le_bytes());
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+    }
+
+    /**
+     * Build a minimal MBR disk image with the given disk signature, two primary partitions,
+     * and an extended partition with a single logical partition. Only the partition table
+     * sectors are filled in.
+     */
+    pub fn mbr_image(disk_sig: u32) -> Vec<u8> {
+        let mut img = vec![0u8; 4096];
+        img[440..444].copy_from_slice(&disk_sig.to_le_bytes());
+        set_mbr_entry(&mut img[..512], 0, 0x0c, 1, 1);
+        set_mbr_entry(&mut img[..512], 1, 0x83, 2, 1);
+        set_mbr_entry(&mut img[..512], 2, 0x05, 4, 4);
+        // first EBR at sector 4, with one logical partition and no next link
+        set_mbr_entry(&mut img[2048..2560], 0, 0x83, 1, 1);
+        img
+    }
+
+    /**
+     * Build a minimal GPT disk image with 512-byte sectors where partition N has unique GUID
+     * bytes [N; 16] and the name "partN".
+     */
+    pub fn gpt_image(nparts: u32) -> Vec<u8> {
+        let mut img = vec![0u8; 512 * 34];
+        set_mbr_entry(&mut img[..512], 0, MBR_TYPE_GPT_PROTECTIVE, 1, 0xffffffff);
+        img[512..520].copy_from_slice(GPT_SIGNATURE);
+        img[(512 + 72)..(512 + 80)].copy_from_slice(&2u64.to_le_bytes());
+        img[(512 + 80)..(512 + 84)].copy_from_slice(&128u32.to_le_bytes());
+        img[(512 + 84)..(512 + 88)].copy_from_slice(&128u32.to_le_bytes());
+        for n in 1..=nparts {
+            let off = 1024 + 128 * (n as usize - 1);
+            img[off..(off + 16)].copy_from_slice(&[0xaf; 16]);
+            img[(off + 16)..(off + 32)].copy_from_slice(&[n as u8; 16]);
+            let name: Vec<u8> = format!("part{}", n)
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes().to_vec())
+                .collect();
+            img[(off + 56)..(off + 56 + name.len())].copy_from_slice(&name);
+        }
+        img
+    }
+
+    #[test]
+    fn test_mbr() {
+        let parts = read_partitions(&mut Cursor::new(mbr_image(0x6c586e13))).unwrap();
+        let uuids: Vec<_> = parts.iter().map(|p| (p.number, p.partuuid.as_str())).collect();
+        assert_eq!(
+            uuids,
+            [(1, "6c586e13-01"), (2, "6c586e13-02"), (3, "6c586e13-03"), (5, "6c586e13-05")]
+        );
+        assert_eq!(parts[0].partlabel, None);
+    }
+
+    #[test]
+    fn test_gpt() {
+        let parts = read_partitions(&mut Cursor::new(gpt_image(3))).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[1].number, 2);
+        assert_eq!(parts[1].partuuid, "02020202-0202-0202-0202-020202020202");
+        assert_eq!(parts[1].partlabel.as_deref(), Some("part2"));
+
+        // entry size, entry count, and table location that are out of range or overflow
+        let bad: [(usize, &[u8]); 4] = [
+            (84, &0x10000u32.to_le_bytes()),
+            (84, &192u32.to_le_bytes()),
+            (80, &1025u32.to_le_bytes()),
+            (72, &u64::MAX.to_le_bytes()),
+        ];
+        for (pos, bytes) in bad.iter() {
+            let mut img = gpt_image(1);
+            img[(512 + pos)..(512 + pos + bytes.len())].copy_from_slice(bytes);
+            let err = read_partitions(&mut Cursor::new(img)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_format_guid() {
+        let bytes = [
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ];
+        assert_eq!(format_guid(&bytes), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+    }
+
+    #[test]
+    fn test_no_table() {
+        assert!(read_partitions(&mut Cursor::new(vec![0u8; 1024])).is_err());
+        assert!(read_partitions(&mut Cursor::new(vec![0u8; 10])).is_err());
+    }
+}
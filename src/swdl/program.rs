@@ -6,49 +6,63 @@
  * SPDX-License-Identifier: GPL-3.0-or-later
  */
 
-use std::cmp::min;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use yall::log_macros::*;
-use zstd::stream::write::Decoder as ZstdWriteDecoder;
 
+use nimage::decode;
 use nimage::format::*;
+use nimage::reader::PartReader;
 use nimage::util::human_size;
-use nimage::xxhio;
 
-use crate::flashbanks::raw_dest_path;
+use crate::boottar::{self, StagedTar};
+use crate::flashbanks::{raw_dest_path, BankLayout};
 use crate::input::Input;
+use crate::mounts::{release_device, MountPolicy};
+use crate::safety::{check_not_in_use, SystemDevices};
 
 const BLOCK_SIZE: usize = 256 * 1024;
 
-/// Write wrapper that counts the number of bytes written in an external u64.
-/// Slightly hacky, but works around the fact that this will get hidden somewhere
-/// in a Box<dyn Write>.
-struct CountWriter<'a, W> {
-    inner: W,
-    count: &'a mut u64,
+/// Read wrapper that updates a progress bar with the number of bytes read so far
+struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a ProgressBar,
+    count: u64,
 }
 
-impl<'a, W> CountWriter<'a, W> {
-    pub fn new(inner: W, count: &'a mut u64) -> Self {
-        Self { inner, count }
+impl<'a, R: Read> ProgressReader<'a, R> {
+    fn new(inner: R, progress: &'a ProgressBar) -> Self {
+        ProgressReader { inner, progress, count: 0 }
     }
 }
 
-impl<'a, W: Write> Write for CountWriter<'a, W> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let count = self.inner.write(buf)?;
-        *self.count += count as u64;
+impl<'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.count += count as u64;
+        self.progress.set_position(self.count);
         Ok(count)
     }
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.inner.flush()
+}
+
+/// Read from reader until buf is full or EOF is reached. Returns the number of bytes read,
+/// which is less than buf.len() only at EOF.
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(count) => total += count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
     }
+    Ok(total)
 }
 
 fn make_progress_bar(size: u64) -> ProgressBar {
@@ -57,105 +71,207 @@ fn make_progress_bar(size: u64) -> ProgressBar {
     pb
 }
 
-/// program a raw partition nImage part.
-/// Returns the number of bytes written to disk (after decompression, if applicable)
-fn program_raw<P: AsRef<Path>>(
-    input: &mut Input,
-    dest: P,
-    part: &PartHeader,
-    progress: &ProgressBar,
-) -> Result<u64> {
-    if part.comp == CompMode::None {
-        info!("Programming part {}", part.ptype);
-    } else {
-        info!("Programming part {} compressed with {}", part.ptype, part.comp);
+/**
+ * A part for the shared boot partition which has been read and verified, but not yet written
+ * to its final location. Dropping this without calling commit() leaves the boot partition
+ * untouched.
+ */
+#[derive(Debug)]
+pub enum StagedPart {
+    /// raw BootImg data, still compressed with comp, in an anonymous temporary file
+    Raw { ptype: PartType, comp: CompMode, data: File, dest: PathBuf, mounted: MountPolicy },
+    /// BootTar contents, extracted into a staging directory in /boot
+    Tar(StagedTar),
+}
+
+impl StagedPart {
+    /**
+     * Write the staged part to its final location. Returns the number of bytes written.
+     */
+    pub fn commit(self) -> Result<u64> {
+        match self {
+            StagedPart::Raw { ptype, comp, mut data, dest, mounted } => {
+                info!("Programming staged part {}", ptype);
+                info!("Writing to {}", dest.to_string_lossy());
+                data.seek(SeekFrom::Start(0)).context("failed to rewind staged data")?;
+                let progress = make_progress_bar(data.metadata()?.len());
+                let ret = decode::reader(comp, ProgressReader::new(data, &progress))
+                    .with_context(|| format!("failed to initialize {} decompressor", comp))
+                    .and_then(|mut reader| write_blocks(&mut reader, &dest, mounted));
+                progress.finish_at_current_pos();
+                ret
+            }
+            StagedPart::Tar(staged) => staged.commit(),
+        }
     }
+}
 
-    let dest_string = dest.as_ref().to_string_lossy();
-    info!("Writing to {}", dest_string);
+/// Copy everything from reader into the existing file or block device dest, after handling
+/// any filesystems mounted from it according to the mount policy.
+/// Returns the number of bytes written.
+fn write_blocks<R: Read>(reader: &mut R, dest: &Path, mounted: MountPolicy) -> Result<u64> {
+    let dest_string = dest.to_string_lossy();
+    // declared before outfile so that if anything fails, it's dropped (and anything unmounted
+    // is mounted again) after outfile is closed
+    let unmounted = release_device(dest, mounted)?;
+    check_not_in_use(&SystemDevices, dest)?;
 
     // open output with the equivalent of open(dest, O_WRONLY | O_SYNC), without O_TRUNC or O_CREAT
     // Using O_SYNC is slightly slower, but it makes the progress bar smoother and eliminates the
     // big fsync delay when the outfile's file descriptor is closed. We write in pretty big chunks
     // so the extra overhead is measurable but small, on the order of a second or two.
-    let outfile = OpenOptions::new()
+    let mut outfile = OpenOptions::new()
         .write(true)
         .custom_flags(libc::O_SYNC)
-        .open(&dest)
+        .open(dest)
         .with_context(|| format!("failed to open output '{}' for writing", dest_string))?;
 
-    // count how many bytes we wrote to disk (after decompression)
+    // Always write full blocks, since small writes are slow with O_SYNC.
     let mut out_count = 0;
-    let outfile = CountWriter::new(outfile, &mut out_count);
-
-    // we have to create and wrap the output in two steps because Box::<dyn Write>::new(...) fails
-    // with weird error messages.
-    // The xxhio Writer is outside of the decompressor so that the hash is computed against the
-    // compressed data rather than the uncompressed data.
-    // TODO: refactor this with a explicit type that more gracefully handles keeping track of the
-    // xxHash and the decompressed bytes written.
-    let out: Box<dyn Write> = match part.comp {
-        CompMode::None => Box::new(outfile),
-        CompMode::Zstd => Box::new(
-            ZstdWriteDecoder::new(outfile).context("failed to initialized zstd compressor")?,
-        ),
-        CompMode::LibArchive => return Err(anyhow!("part comp mode {} is unsupported", part.comp)),
-    };
-    let mut out = xxhio::Writer::new(out);
-
-    // do the data copy
     let mut buf = vec![0u8; BLOCK_SIZE];
-    let mut total = 0;
-    while total < part.size {
-        let to_read = min(BLOCK_SIZE, (part.size - total) as usize);
-        let count = match input.read(&mut buf[..to_read]) {
-            Ok(0) => return Err(anyhow!("EOF after reading only {}/{} bytes", total, part.size)),
-            Ok(c) => c,
-            Err(e) => return Err(e).context("failed to read input"),
-        };
-
-        out.write_all(&buf[..count])?;
-        total += count as u64;
-        progress.set_position(total);
+    loop {
+        let count = read_block(reader, &mut buf).context("failed to read input")?;
+        if count == 0 {
+            break;
+        }
+        outfile.write_all(&buf[..count]).context("failed to write output")?;
+        out_count += count as u64;
     }
 
-    let hash = out.hash();
-    if hash != part.xxh {
-        return Err(anyhow!("xxHash mismatch! Expected 0x{:08X} got 0x{:08X}", part.xxh, hash));
+    // close the device before mounting anything on it again
+    drop(outfile);
+    unmounted.remount()?;
+    Ok(out_count)
+}
+
+fn log_part(part: &PartHeader) {
+    if part.comp == CompMode::None {
+        info!("Programming part {}", part.ptype);
+    } else {
+        info!("Programming part {} compressed with {}", part.ptype, part.comp);
     }
+}
+
+/// program a raw partition nImage part.
+/// Returns the number of bytes written to disk (after decompression, if applicable)
+fn program_raw(
+    mut input: PartReader<Input>,
+    dest: &Path,
+    mounted: MountPolicy,
+    part: &PartHeader,
+    progress: &ProgressBar,
+) -> Result<u64> {
+    log_part(part);
+    info!("Writing to {}", dest.to_string_lossy());
+
+    // The progress bar tracks the compressed data, since that's what the part size is
+    let mut raw = ProgressReader::new(&mut input, progress);
+
+    // do the data copy, counting how many bytes we wrote to disk (after decompression).
+    let out_count = {
+        let mut reader = decode::reader(part.comp, &mut raw)
+            .with_context(|| format!("failed to initialize {} decompressor", part.comp))?;
+        write_blocks(&mut reader, dest, mounted)?
+    };
 
-    // out owns outfile, which contains an exclusive reference to out_count.
-    // Drop it so we can use out_count again.
-    std::mem::drop(out);
+    // The decoder may stop before the end of the part data (e.g. trailing padding after a
+    // compressed stream), finish() reads the rest and verifies the size and hashes.
+    input.finish()?;
     Ok(out_count)
 }
 
-pub fn program_part(input: &mut Input, part: &PartHeader) -> Result<()> {
+/// stage a raw partition nImage part in a temporary file, to be written to dest later.
+/// The part data is staged as-is and only decompressed when it's committed, so the temporary
+/// file in $TMPDIR (default /tmp) only needs space for the compressed part.
+fn stage_raw(
+    mut input: PartReader<Input>,
+    dest: PathBuf,
+    mounted: MountPolicy,
+    part: &PartHeader,
+    progress: &ProgressBar,
+) -> Result<StagedPart> {
+    log_part(part);
+    info!("Staging data for {} in a temporary file", dest.to_string_lossy());
+
+    let mut data = tempfile::tempfile().context("failed to create temporary file")?;
+    io::copy(&mut ProgressReader::new(&mut input, progress), &mut data)
+        .context("failed to stage part data")?;
+    input.finish()?;
+
+    // decompress it once now, so that a bad stream is caught before anything is written
+    data.seek(SeekFrom::Start(0)).context("failed to rewind staged data")?;
+    let size = {
+        let mut reader = decode::reader(part.comp, &mut data)
+            .with_context(|| format!("failed to initialize {} decompressor", part.comp))?;
+        io::copy(&mut reader, &mut io::sink()).context("failed to decompress part data")?
+    };
+    debug!("staged part decompresses to {}", human_size(size));
+    Ok(StagedPart::Raw { ptype: part.ptype, comp: part.comp, data, dest, mounted })
+}
+
+/// extract a tar archive nImage part into a staging directory next to dest, the archive is
+/// only moved into place when the returned StagedTar is committed.
+fn stage_tar(
+    mut input: PartReader<Input>,
+    dest: &Path,
+    part: &PartHeader,
+    progress: &ProgressBar,
+) -> Result<StagedTar> {
+    log_part(part);
+    info!("Extracting to {}", dest.to_string_lossy());
+
+    let mut raw = ProgressReader::new(&mut input, progress);
+    let staged = {
+        let reader = decode::reader(part.comp, &mut raw)
+            .with_context(|| format!("failed to initialize {} decompressor", part.comp))?;
+        boottar::stage(reader, dest)?
+    };
+
+    // The tar reader stops at the end-of-archive marker, finish() consumes whatever's left of
+    // the part (trailing zero blocks, padding) so that it's included in the hash.
+    input.finish()?;
+    Ok(staged)
+}
+
+/**
+ * Program a single part to the location given by the bank layout. The part is verified
+ * after it's written.
+ *
+ * Parts which belong on the shared boot partition (BootImg and BootTar) are only staged and
+ * verified, and returned for the caller to commit once the rest of the image has been verified
+ * too. Rootfs parts are written directly to the inactive bank.
+ */
+pub fn program_part(input: PartReader<Input>, layout: &BankLayout) -> Result<Option<StagedPart>> {
+    let part = input.header().clone();
     // set up the progress bar here so that we can control what happens if the inner function fails
     // in the middle of writing.
     let progress = make_progress_bar(part.size);
 
     let ret = match part.ptype {
-        PartType::BootImg | PartType::Rootfs | PartType::RootfsRw => {
-            // FIXME: unmount and remount /boot, or at least check that /boot isn't mounted
-            let dest_path = raw_dest_path(part.ptype)?;
-            program_raw(input, dest_path, part, &progress)
+        PartType::BootImg => {
+            let dest_path = raw_dest_path(layout, part.ptype)?;
+            stage_raw(input, PathBuf::from(dest_path), layout.mounted, &part, &progress).map(Some)
         }
-        PartType::BootTar | PartType::Invalid => {
-            // FIXME: actually implement tar part types
-            Err(anyhow!("unsupported part type {}", part.ptype))
+        PartType::Rootfs | PartType::RootfsRw => {
+            let dest_path = raw_dest_path(layout, part.ptype)?;
+            program_raw(input, Path::new(dest_path), layout.mounted, &part, &progress).map(
+                |written| {
+                    info!("Read: {}, Wrote: {}", human_size(part.size), human_size(written));
+                    None
+                },
+            )
         }
+        PartType::BootTar => stage_tar(input, &layout.boot_dir, &part, &progress)
+            .map(|staged| Some(StagedPart::Tar(staged))),
+        PartType::Invalid => Err(anyhow!("unsupported part type {}", part.ptype)),
     };
 
     // finish the progress bar after the inner function fails, leave its position as-is if it
     // failed in the middle of writing.
     progress.finish_at_current_pos();
 
-    match ret {
-        Ok(written) => {
-            info!("Read: {}, Wrote: {}", human_size(part.size), human_size(written));
-            Ok(())
-        }
-        Err(err) => Err(err),
+    if let Ok(Some(_)) = ret {
+        info!("Read: {}, staged until the rest of the image is verified", human_size(part.size));
     }
+    ret
 }
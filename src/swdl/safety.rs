@@ -0,0 +1,280 @@
+/*!
+ * swdl: Raspberry Pi firmware update engine.
+ * last-chance check that a device isn't in use before it's programmed
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * The destination of a raw part is worked out from the bank layout and the kernel cmdline,
+ * and a mistake in either could point swdl at the running rootfs. Right before writing, the
+ * destination's device number is compared against the device of / and of every mounted
+ * filesystem, independently of how the destination was chosen. Writing to the disk that holds
+ * one of those, or to a device under an LVM or device-mapper volume that holds one, is refused
+ * too.
+ */
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::mounts::{read_mountinfo, split_dev};
+
+/**
+ * Where device numbers come from. This is the real system for swdl, and a fake in tests so
+ * that the checks can run on any host.
+ */
+pub trait Devices {
+    /// Device number (st_rdev) of the block device at path, or None if it's not a block device
+    fn block_device(&self, path: &Path) -> io::Result<Option<(u32, u32)>>;
+
+    /// Device number (st_dev) of the filesystem containing path
+    fn filesystem_dev(&self, path: &Path) -> io::Result<(u32, u32)>;
+
+    /// Device number and mount point of every mounted filesystem
+    fn mounted(&self) -> Result<Vec<((u32, u32), PathBuf)>>;
+
+    /// The whole disk that contains dev, or None if dev isn't a partition
+    fn parent(&self, dev: (u32, u32)) -> io::Result<Option<(u32, u32)>>;
+
+    /// The devices that dev is built on top of, e.g. the physical volumes of an LVM volume
+    fn slaves(&self, dev: (u32, u32)) -> io::Result<Vec<(u32, u32)>>;
+}
+
+/// Directory in sysfs for a block device
+fn sys_block_dir(dev: (u32, u32)) -> PathBuf {
+    PathBuf::from(format!("/sys/dev/block/{}:{}", dev.0, dev.1))
+}
+
+/// Read a sysfs "dev" file, which holds a device number as "MAJ:MIN"
+fn read_dev_file(path: &Path) -> io::Result<(u32, u32)> {
+    let s = fs::read_to_string(path)?;
+    let mut parts = s.trim().splitn(2, ':').map(|n| n.parse::<u32>().ok());
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(major), Some(minor)) => Ok((major, minor)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid device number in '{}'", path.display()),
+        )),
+    }
+}
+
+/// The devices of the running system
+pub struct SystemDevices;
+
+impl Devices for SystemDevices {
+    fn block_device(&self, path: &Path) -> io::Result<Option<(u32, u32)>> {
+        let meta = fs::metadata(path)?;
+        Ok(if meta.file_type().is_block_device() { Some(split_dev(meta.rdev())) } else { None })
+    }
+
+    fn filesystem_dev(&self, path: &Path) -> io::Result<(u32, u32)> {
+        Ok(split_dev(fs::metadata(path)?.dev()))
+    }
+
+    fn mounted(&self) -> Result<Vec<((u32, u32), PathBuf)>> {
+        // mountinfo has the same device numbers as st_dev, without having to stat every mount
+        // point (which could hang on a network filesystem)
+        Ok(read_mountinfo()?.into_iter().map(|m| (m.dev, m.mount_point)).collect())
+    }
+
+    fn parent(&self, dev: (u32, u32)) -> io::Result<Option<(u32, u32)>> {
+        // a partition's sysfs directory has a "partition" file, and is inside its disk's
+        let dir = sys_block_dir(dev);
+        if !dir.join("partition").exists() {
+            return Ok(None);
+        }
+        read_dev_file(&dir.join("../dev")).map(Some)
+    }
+
+    fn slaves(&self, dev: (u32, u32)) -> io::Result<Vec<(u32, u32)>> {
+        let entries = match fs::read_dir(sys_block_dir(dev).join("slaves")) {
+            Ok(entries) => entries,
+            // not a block device (e.g. proc), or nothing underneath it
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        entries.map(|entry| read_dev_file(&entry?.path().join("dev"))).collect()
+    }
+}
+
+/**
+ * Every device that writing to would clobber the filesystem on dev: dev itself, the devices
+ * it's built on (recursively), and the disks that all of those are partitions of.
+ */
+fn underlying_devices<D: Devices>(devices: &D, dev: (u32, u32)) -> Result<Vec<(u32, u32)>> {
+    let mut found = vec![dev];
+    let mut i = 0;
+    while i < found.len() {
+        let cur = found[i];
+        let mut next = devices
+            .slaves(cur)
+            .with_context(|| format!("failed to list devices under {}:{}", cur.0, cur.1))?;
+        if let Some(parent) = devices
+            .parent(cur)
+            .with_context(|| format!("failed to find the disk of {}:{}", cur.0, cur.1))?
+        {
+            next.push(parent);
+        }
+        for d in next {
+            if !found.contains(&d) {
+                found.push(d);
+            }
+        }
+        i += 1;
+    }
+    Ok(found)
+}
+
+/**
+ * Check that dest isn't the device of the running rootfs or any other mounted filesystem,
+ * returning an error that explains which one it is. Anything that isn't a block device
+ * (e.g. /dev/null or a regular file) is always allowed.
+ */
+pub fn check_not_in_use<D: Devices>(devices: &D, dest: &Path) -> Result<()> {
+    let dev = match devices
+        .block_device(dest)
+        .with_context(|| format!("failed to stat '{}'", dest.display()))?
+    {
+        Some(dev) => dev,
+        None => return Ok(()),
+    };
+
+    let root_dev = devices.filesystem_dev(Path::new("/")).context("failed to stat /")?;
+    if dev == root_dev {
+        return Err(anyhow!(
+            "refusing to program {} (device {}:{}), it holds the running root filesystem",
+            dest.display(),
+            dev.0,
+            dev.1
+        ));
+    }
+
+    let mounted = devices.mounted()?;
+    if let Some((_, mount_point)) = mounted.iter().find(|(d, _)| *d == dev) {
+        return Err(anyhow!(
+            "refusing to program {} (device {}:{}), it's mounted at {}",
+            dest.display(),
+            dev.0,
+            dev.1,
+            mount_point.display()
+        ));
+    }
+
+    // the whole disk, or a device under a volume, which holds one of them
+    if underlying_devices(devices, root_dev)?.contains(&dev) {
+        return Err(anyhow!(
+            "refusing to program {} (device {}:{}), the running root filesystem is on it",
+            dest.display(),
+            dev.0,
+            dev.1
+        ));
+    }
+    for (mounted_dev, mount_point) in mounted.iter() {
+        if underlying_devices(devices, *mounted_dev)?.contains(&dev) {
+            return Err(anyhow!(
+                "refusing to program {} (device {}:{}), the filesystem mounted at {} is on it",
+                dest.display(),
+                dev.0,
+                dev.1,
+                mount_point.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * A fake system with the rootfs on 179:2 and /boot on 179:1, both partitions of the SD card
+     * 179:0, and /data on the LVM volume 253:0 which is on 8:1, a partition of 8:0.
+     */
+    struct FakeDevices;
+
+    impl Devices for FakeDevices {
+        fn block_device(&self, path: &Path) -> io::Result<Option<(u32, u32)>> {
+            match path.to_str().unwrap() {
+                "/dev/mmcblk0p1" => Ok(Some((179, 1))),
+                "/dev/mmcblk0p2" => Ok(Some((179, 2))),
+                "/dev/mmcblk0p3" => Ok(Some((179, 3))),
+                // a differently named node for the same device
+                "/dev/root" => Ok(Some((179, 2))),
+                "/dev/mmcblk0" => Ok(Some((179, 0))),
+                "/dev/sda" => Ok(Some((8, 0))),
+                "/dev/sda1" => Ok(Some((8, 1))),
+                "/dev/sdb" => Ok(Some((8, 16))),
+                "/dev/null" => Ok(None),
+                _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+            }
+        }
+
+        fn filesystem_dev(&self, path: &Path) -> io::Result<(u32, u32)> {
+            assert_eq!(path, Path::new("/"));
+            Ok((179, 2))
+        }
+
+        fn mounted(&self) -> Result<Vec<((u32, u32), PathBuf)>> {
+            Ok(vec![
+                ((179, 2), PathBuf::from("/")),
+                ((0, 21), PathBuf::from("/proc")),
+                ((179, 1), PathBuf::from("/boot")),
+                ((253, 0), PathBuf::from("/data")),
+            ])
+        }
+
+        fn parent(&self, dev: (u32, u32)) -> io::Result<Option<(u32, u32)>> {
+            Ok(match dev {
+                (179, 1..=3) => Some((179, 0)),
+                (8, 1) => Some((8, 0)),
+                _ => None,
+            })
+        }
+
+        fn slaves(&self, dev: (u32, u32)) -> io::Result<Vec<(u32, u32)>> {
+            Ok(if dev == (253, 0) { vec![(8, 1)] } else { Vec::new() })
+        }
+    }
+
+    #[test]
+    fn test_check_not_in_use() {
+        let check = |dest: &str| check_not_in_use(&FakeDevices, Path::new(dest));
+        check("/dev/mmcblk0p3").unwrap();
+        check("/dev/null").unwrap();
+        assert!(check("/dev/missing").is_err());
+
+        let err = check("/dev/mmcblk0p2").unwrap_err().to_string();
+        assert!(err.contains("running root filesystem"), "{}", err);
+        let err = check("/dev/root").unwrap_err().to_string();
+        assert!(err.contains("179:2"), "{}", err);
+
+        let err = check("/dev/mmcblk0p1").unwrap_err().to_string();
+        assert!(err.contains("mounted at /boot"), "{}", err);
+
+        // the disks and volume members under mounted filesystems
+        let err = check("/dev/mmcblk0").unwrap_err().to_string();
+        assert!(err.contains("running root filesystem is on it"), "{}", err);
+        let err = check("/dev/sda1").unwrap_err().to_string();
+        assert!(err.contains("mounted at /data is on it"), "{}", err);
+        let err = check("/dev/sda").unwrap_err().to_string();
+        assert!(err.contains("mounted at /data is on it"), "{}", err);
+        check("/dev/sdb").unwrap();
+    }
+
+    #[test]
+    fn test_system_devices() {
+        // whatever this host is, / can't be programmed if it's on a block device, and
+        // character devices are always allowed
+        let devices = SystemDevices;
+        assert!(devices.mounted().unwrap().iter().any(|(_, mp)| mp == Path::new("/")));
+        check_not_in_use(&devices, Path::new("/dev/null")).unwrap();
+        // /proc isn't a block device, so it has no disk or slaves
+        let proc_dev = devices.filesystem_dev(Path::new("/proc")).unwrap();
+        assert_eq!(devices.parent(proc_dev).unwrap(), None);
+        assert!(devices.slaves(proc_dev).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,350 @@
+/*!
+ * swdl: Raspberry Pi firmware update engine.
+ * persistent update state
+ *
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * swdl records the progress of each update in a small JSON file, so that an update which was
+ * interrupted (e.g. by a power cut) can be recognized afterwards, and either resumed or
+ * aborted. The normal sequence of states is
+ *
+ *   idle -> writing -> written -> pending-boot -> confirmed (or rolled-back)
+ *
+ * where "writing" means the inactive bank (and possibly the boot partition) is being
+ * programmed, "written" means every part is programmed and verified but the bootloader hasn't
+ * been switched to the new rootfs yet, and "pending-boot" means the switch is done and the
+ * new image runs on the next boot (or the next tryboot). Once the new image is running and
+ * healthy, "swdl confirm" marks it good, or "swdl rollback" goes back to the previous rootfs.
+ *
+ * The new image has to find the state that the old one saved, so the file is kept on the boot
+ * partition by default rather than in either rootfs bank.
+ */
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use yall::log_macros::*;
+
+use crate::flashbanks::update_rootfs;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateState {
+    /// no update has been done, or an unfinished one was aborted
+    Idle,
+    /// programming parts
+    Writing,
+    /// every part is programmed and verified, the rootfs switch is next
+    Written,
+    /// the bootloader is switched to the new image, waiting for a reboot
+    PendingBoot,
+    /// the new image booted and was marked good
+    Confirmed,
+    /// the new image was abandoned and the previous one restored
+    RolledBack,
+}
+
+impl Default for UpdateState {
+    fn default() -> Self {
+        UpdateState::Idle
+    }
+}
+
+impl fmt::Display for UpdateState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UpdateState::Idle => "idle",
+            UpdateState::Writing => "writing",
+            UpdateState::Written => "written",
+            UpdateState::PendingBoot => "pending-boot",
+            UpdateState::Confirmed => "confirmed",
+            UpdateState::RolledBack => "rolled-back",
+        })
+    }
+}
+
+impl UpdateState {
+    /// Whether an update was started but didn't get as far as switching the rootfs
+    pub fn is_interrupted(self) -> bool {
+        matches!(self, UpdateState::Writing | UpdateState::Written)
+    }
+
+    /**
+     * Check whether moving from this state to next is allowed. A new update can start from
+     * any state, everything else has to follow the normal sequence, except that an
+     * interrupted update can be aborted back to idle, and a confirmed update can still be
+     * rolled back.
+     */
+    pub fn can_move_to(self, next: UpdateState) -> bool {
+        use UpdateState::*;
+        matches!(
+            (self, next),
+            (_, Writing)
+                | (Writing, Written)
+                | (Written, PendingBoot)
+                | (Writing, Idle)
+                | (Written, Idle)
+                | (PendingBoot, Confirmed)
+                | (PendingBoot, RolledBack)
+                | (Confirmed, RolledBack)
+        )
+    }
+}
+
+/**
+ * Everything recorded about the current (or most recent) update.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct UpdateStatus {
+    pub state: UpdateState,
+    /// image file path or URL
+    pub image: Option<String>,
+    /// detached signature file path or URL, None if the signature wasn't checked
+    pub signature: Option<String>,
+    /// name from the image header
+    pub image_name: Option<String>,
+    /// root= value of the new rootfs, None if the image has no rootfs part
+    pub rootfs: Option<String>,
+    /// whether the new rootfs is mounted read-write
+    pub rootfs_rw: bool,
+    /// whether the new rootfs is set up for tryboot, rather than switched to permanently
+    pub tryboot: bool,
+    /// contents of cmdline.txt before the update, for rolling back
+    pub previous_cmdline: Option<String>,
+    /// time of the last state change, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl UpdateStatus {
+    /**
+     * Load the update status from path. A missing file means that swdl has never run,
+     * which is the idle state.
+     */
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s)
+                .with_context(|| format!("failed to parse update state '{}'", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("{} not found, no update has been done", path.display());
+                Ok(Self::default())
+            }
+            Err(e) => {
+                Err(e).with_context(|| format!("failed to read update state '{}'", path.display()))
+            }
+        }
+    }
+
+    /**
+     * Move to a new state and save it to path. The file is replaced atomically, so it always
+     * holds either the old or the new state, even after a power cut.
+     */
+    pub fn set_state(&mut self, path: &Path, state: UpdateState) -> Result<()> {
+        if !self.state.can_move_to(state) {
+            return Err(anyhow!("can't change update state from {} to {}", self.state, state));
+        }
+        debug!("update state {} -> {}", self.state, state);
+        self.state = state;
+        self.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.save(path)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory '{}'", dir.display()))?;
+
+        let mut tmp = NamedTempFile::new_in(dir)
+            .with_context(|| format!("failed to create temp file in '{}'", dir.display()))?;
+        serde_json::to_writer_pretty(&mut tmp, self)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(tmp))
+            .and_then(|_| tmp.as_file().sync_all())
+            .with_context(|| format!("failed to write '{}'", tmp.path().display()))?;
+        tmp.persist(path).with_context(|| format!("failed to replace '{}'", path.display()))?;
+
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .with_context(|| format!("failed to sync directory '{}'", dir.display()))
+    }
+
+    /**
+     * Work out the cmdline.txt to roll back to if a new update is started now, given the
+     * current contents of cmdline.txt. Normally that's the current cmdline, but if the last
+     * update already switched cmdline.txt and hasn't been booted yet, the current one doesn't
+     * boot the running rootfs. In that case the cmdline saved by the last update is kept, or
+     * if there isn't one, the current cmdline is pointed back at running_root().
+     */
+    pub fn rollback_cmdline<F>(&self, cmdline: &str, running_root: F) -> Result<String>
+    where
+        F: FnOnce() -> Result<String>,
+    {
+        match (self.state, &self.previous_cmdline) {
+            // an interrupted update saved its previous cmdline the same way
+            (UpdateState::PendingBoot, Some(previous))
+            | (UpdateState::Writing, Some(previous))
+            | (UpdateState::Written, Some(previous)) => Ok(previous.clone()),
+            // tryboot leaves cmdline.txt alone until the update is confirmed
+            (UpdateState::PendingBoot, None) if !self.tryboot => {
+                let rw = cmdline.split_ascii_whitespace().any(|w| w == "rw");
+                Ok(update_rootfs(cmdline, &running_root()?, rw) + "\n")
+            }
+            _ => Ok(cmdline.to_string()),
+        }
+    }
+
+    /**
+     * Print the status in a human-readable format
+     */
+    pub fn print_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let none = String::from("none");
+        writeln!(w, "State:      {}", self.state)?;
+        if self.state != UpdateState::Idle {
+            writeln!(w, "Image:      {}", self.image.as_ref().unwrap_or(&none))?;
+            writeln!(w, "Image Name: {}", self.image_name.as_ref().unwrap_or(&none))?;
+            writeln!(w, "Signature:  {}", self.signature.as_ref().unwrap_or(&none))?;
+            match self.rootfs {
+                Some(ref root) => writeln!(
+                    w,
+                    "Rootfs:     {} {}{}",
+                    root,
+                    if self.rootfs_rw { "rw" } else { "ro" },
+                    if self.tryboot { " (tryboot)" } else { "" }
+                )?,
+                None => writeln!(w, "Rootfs:     none")?,
+            }
+        }
+        if self.timestamp != 0 {
+            writeln!(w, "Updated:    {} (seconds since the epoch)", self.timestamp)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        use UpdateState::*;
+        let sequence = [Idle, Writing, Written, PendingBoot, Confirmed];
+        for pair in sequence.windows(2) {
+            assert!(pair[0].can_move_to(pair[1]), "{} -> {}", pair[0], pair[1]);
+        }
+        assert!(PendingBoot.can_move_to(RolledBack));
+        assert!(Written.can_move_to(Idle));
+        assert!(Confirmed.can_move_to(Writing));
+        assert!(Confirmed.can_move_to(RolledBack));
+
+        assert!(!Idle.can_move_to(Written));
+        assert!(!Writing.can_move_to(PendingBoot));
+        assert!(!PendingBoot.can_move_to(Idle));
+        assert!(!RolledBack.can_move_to(Confirmed));
+
+        assert!(Writing.is_interrupted());
+        assert!(Written.is_interrupted());
+        assert!(!PendingBoot.is_interrupted());
+    }
+
+    #[test]
+    fn test_update_update_rollback() {
+        use crate::flashbanks::{read_cmdline, switch_rootfs, write_boot_file};
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        let original = "console=tty1 root=/dev/mmcblk0p2 ro rootwait\n";
+        write_boot_file(dir.path(), "cmdline.txt", original).unwrap();
+        let running_root = || Ok(String::from("/dev/mmcblk0p2"));
+
+        // two updates to the inactive bank in a row, without rebooting in between
+        let mut status = UpdateStatus::default();
+        for _ in 0..2 {
+            let cmdline = read_cmdline(dir.path()).unwrap();
+            status.previous_cmdline =
+                Some(status.rollback_cmdline(&cmdline, running_root).unwrap());
+            status.set_state(&state_file, UpdateState::Writing).unwrap();
+            status.set_state(&state_file, UpdateState::Written).unwrap();
+            switch_rootfs(dir.path(), "/dev/mmcblk0p3", false).unwrap();
+            status.set_state(&state_file, UpdateState::PendingBoot).unwrap();
+        }
+
+        // rolling back goes to the rootfs that's running, not the first update
+        assert_eq!(status.previous_cmdline.as_deref(), Some(original));
+
+        // an older state file without the previous cmdline gets the running rootfs put back
+        status.previous_cmdline = None;
+        let cmdline = read_cmdline(dir.path()).unwrap();
+        assert_eq!(status.rollback_cmdline(&cmdline, running_root).unwrap(), original);
+
+        // once the update is confirmed, cmdline.txt is what's running
+        status.set_state(&state_file, UpdateState::Confirmed).unwrap();
+        assert_eq!(status.rollback_cmdline(&cmdline, running_root).unwrap(), cmdline);
+
+        // a pending tryboot update hasn't touched cmdline.txt, so it's kept as it is
+        let tryboot =
+            UpdateStatus { state: UpdateState::PendingBoot, tryboot: true, ..Default::default() };
+        assert_eq!(tryboot.rollback_cmdline(original, running_root).unwrap(), original);
+    }
+
+    #[test]
+    fn test_state_shared_by_banks() {
+        use crate::flashbanks::BankLayout;
+        // each rootfs bank has its own copy of the config, and only the boot partition is
+        // shared between them
+        let boot = tempfile::tempdir().unwrap();
+        let config = format!("boot_dir = \"{}\"\n", boot.path().display());
+        let bank_a: BankLayout = config.parse().unwrap();
+        let mut status = UpdateStatus {
+            rootfs: Some(String::from("/dev/mmcblk0p3")),
+            previous_cmdline: Some(String::from("root=/dev/mmcblk0p2\n")),
+            ..Default::default()
+        };
+        for state in [UpdateState::Writing, UpdateState::Written, UpdateState::PendingBoot].iter() {
+            status.set_state(&bank_a.state_file(), *state).unwrap();
+        }
+
+        // after rebooting into the other bank, the update can be confirmed
+        let bank_b: BankLayout = config.parse().unwrap();
+        assert!(bank_b.state_file().starts_with(boot.path()));
+        let mut loaded = UpdateStatus::load(&bank_b.state_file()).unwrap();
+        assert_eq!(loaded, status);
+        loaded.set_state(&bank_b.state_file(), UpdateState::Confirmed).unwrap();
+        assert_eq!(UpdateStatus::load(&bank_a.state_file()).unwrap().state, UpdateState::Confirmed);
+    }
+
+    #[test]
+    fn test_load_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/state.json");
+        let mut status = UpdateStatus::load(&path).unwrap();
+        assert_eq!(status, UpdateStatus::default());
+
+        status.image = Some(String::from("http://example.com/rpi.img"));
+        status.rootfs = Some(String::from("PARTUUID=6c586e13-03"));
+        status.set_state(&path, UpdateState::Writing).unwrap();
+        assert_ne!(status.timestamp, 0);
+        assert_eq!(UpdateStatus::load(&path).unwrap(), status);
+        assert!(fs::read_to_string(&path).unwrap().contains("\"state\": \"writing\""));
+
+        assert!(status.set_state(&path, UpdateState::Confirmed).is_err());
+        assert_eq!(status.state, UpdateState::Writing);
+
+        fs::write(&path, "{ \"state\": \"pending-boot\" }").unwrap();
+        let status = UpdateStatus::load(&path).unwrap();
+        assert_eq!(status.state, UpdateState::PendingBoot);
+        assert_eq!(status.image, None);
+
+        fs::write(&path, "{ \"state\": \"bogus\" }").unwrap();
+        assert!(UpdateStatus::load(&path).is_err());
+    }
+}
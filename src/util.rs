@@ -68,6 +68,24 @@ pub fn human_size_extended(s: u64) -> String {
     format!("{0} ({1}, 0x{1:x})", hs, s)
 }
 
+/**
+ * Encode bytes as lowercase hex.
+ */
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/**
+ * Decode a hex string, ignoring leading and trailing whitespace.
+ */
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).ok()).collect()
+}
+
 /**
  * Extension of io::Cursor for reading numeric fields.
  */
@@ -182,6 +200,28 @@ impl<T: Write> WriteHelper for T {
     }
 }
 
+/**
+ * A writer which throws away everything written to it like io::Sink, but counts how many bytes
+ * were written and how many of them weren't zero. Useful for checking padding.
+ */
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ZeroCounter {
+    pub count: u64,
+    pub nonzero: u64,
+}
+
+impl Write for ZeroCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len() as u64;
+        self.nonzero += buf.iter().filter(|b| **b != 0).count() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 /**
  * An Input stream which implements Read and BufRead an can either be stdin
  * or a file opened for reading.
@@ -237,6 +277,18 @@ impl Read for Input {
     }
 }
 
+/// Only files can seek, seeking stdin always fails
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Stdin(_) => {
+                Err(io::Error::new(io::ErrorKind::Other, "can't seek on standard input"))
+            }
+            Self::File(f, _) => f.seek(pos),
+        }
+    }
+}
+
 impl BufRead for Input {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
         match self {
@@ -310,6 +362,14 @@ mod tests {
         assert_eq!(reader.read_byte(), None);
     }
 
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(b"\x00\x1f\xa0\xff"), "001fa0ff");
+        assert_eq!(from_hex(" 001FA0ff\n"), Some(b"\x00\x1f\xa0\xff".to_vec()));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
     #[test]
     fn test_write_helper_slice() {
         let mut arr = [0u8; 32];
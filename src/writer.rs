@@ -0,0 +1,384 @@
+/*!
+ * Builder for complete nImage files.
+ * Copyright 2020 Allen Wild
+ * SPDX-License-Identifier: GPL-3.0-or-later
+ *
+ * The header comes first in the file but can't be written until every part's size and hashes
+ * are known, so ImageWriter writes a placeholder header, streams the parts after it, and then
+ * seeks back to fill in the real header.
+ *
+ * Outputs which can't seek, like pipes, use StreamWriter instead. It reads every part twice:
+ * once to calculate the header, and again to write the data after the header.
+ */
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use zstd::stream::read::Encoder as ZstdReadEncoder;
+
+use super::digest::{DigestType, PartHasher};
+use super::format::*;
+use super::util::WriteHelper;
+
+/// Options for ImageWriter::add_part
+#[derive(Clone, Debug, Default)]
+pub struct PartOptions {
+    /// Compress the part data with zstd at this level while it's written. The part's
+    /// compression mode must be CompMode::Zstd. If None, the data is written as-is.
+    pub zstd_level: Option<i32>,
+    /// Number of zstd worker threads to try to use, 0 for single-threaded compression
+    pub zstd_threads: u32,
+}
+
+fn invalid_input<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/**
+ * Check that header has room for another part of type ptype, before reading any of its data.
+ */
+fn check_new_part(header: &ImageHeader, ptype: PartType) -> io::Result<()> {
+    if header.parts.len() >= NIMG_MAX_PARTS {
+        return Err(invalid_input(format!(
+            "an image can't have more than {} parts",
+            NIMG_MAX_PARTS
+        )));
+    }
+    if ptype == PartType::Invalid {
+        return Err(invalid_input("invalid part type"));
+    }
+    Ok(())
+}
+
+/**
+ * Copy part data from reader to writer, compressing it if options say to. Returns the
+ * number of bytes written and a PartHasher of those bytes.
+ */
+fn write_part_data<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    comp: CompMode,
+    digest_type: Option<DigestType>,
+    options: &PartOptions,
+) -> io::Result<(u64, PartHasher)> {
+    let reader: Box<dyn Read + '_> = match options.zstd_level {
+        Some(level) => {
+            if comp != CompMode::Zstd {
+                return Err(invalid_input(format!(
+                    "can't compress a part with zstd when its compression mode is {}",
+                    comp
+                )));
+            }
+            let mut zenc = ZstdReadEncoder::new(reader, level)?;
+            if options.zstd_threads > 0 {
+                // try to enable multithreading, but ignore errors if it doesn't work
+                let _ = zenc.multithread(options.zstd_threads);
+            }
+            Box::new(zenc)
+        }
+        None => Box::new(reader),
+    };
+
+    let mut hasher = PartHasher::new(digest_type);
+    let size = io::copy(&mut hasher.reader(reader), writer)?;
+    Ok((size, hasher))
+}
+
+/// Set up the header for a new image, see ImageWriter::with_header()
+fn new_header(mut header: ImageHeader) -> io::Result<ImageHeader> {
+    header.parts.clear();
+    if !Layout::for_version(header.version).map_or(false, |l| l.has_digests) {
+        header.digest_type = None;
+    }
+    header.validate().map_err(invalid_input)?;
+    Ok(header)
+}
+
+/**
+ * Writes an nImage to a seekable output, one part at a time. Call finish() after adding
+ * every part, or the output will be left with an empty placeholder header.
+ */
+pub struct ImageWriter<W: Write + Seek> {
+    inner: W,
+    header: ImageHeader,
+    // position of the header in inner
+    start: u64,
+    // number of bytes written after the end of the header
+    offset: u64,
+}
+
+impl<W: Write + Seek> ImageWriter<W> {
+    /**
+     * Start writing a current version image named name, starting at the current position
+     * of inner.
+     */
+    pub fn new(inner: W, name: &str) -> io::Result<Self> {
+        Self::with_header(inner, ImageHeader::new(name))
+    }
+
+    /**
+     * Start writing an image with the name, version, and digest type from header. Any parts
+     * in header are ignored. Digests are only calculated if the version has them.
+     */
+    pub fn with_header(mut inner: W, header: ImageHeader) -> io::Result<Self> {
+        let header = new_header(header)?;
+
+        // write a placeholder header, the real one is written by finish()
+        let start = inner.seek(SeekFrom::Current(0))?;
+        inner.write_zeros(header.header_size())?;
+        Ok(ImageWriter { inner, header, start, offset: 0 })
+    }
+
+    /// The image header, with all the parts added so far
+    pub fn header(&self) -> &ImageHeader {
+        &self.header
+    }
+
+    /**
+     * Add a part to the image, reading the data from reader until EOF. Returns the header
+     * of the new part.
+     */
+    pub fn add_part<R: Read>(
+        &mut self,
+        reader: R,
+        ptype: PartType,
+        comp: CompMode,
+        options: &PartOptions,
+    ) -> io::Result<&PartHeader> {
+        check_new_part(&self.header, ptype)?;
+        let (size, hasher) =
+            write_part_data(reader, &mut self.inner, comp, self.header.digest_type, options)?;
+        let part = PartHeader {
+            size,
+            offset: self.offset,
+            ptype,
+            comp,
+            xxh: hasher.xxh(),
+            digest: hasher.digest(),
+        };
+        self.offset += size;
+
+        let padding = part_padding(size);
+        if padding > 0 {
+            self.inner.write_zeros(padding as usize)?;
+            self.offset += padding;
+        }
+
+        self.header.parts.push(part);
+        Ok(self.header.parts.last().unwrap())
+    }
+
+    /**
+     * Write the real image header and return the output, positioned at the end of the image.
+     */
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.seek(SeekFrom::Start(self.start))?;
+        self.header.write_to(&mut self.inner)?;
+        self.inner.seek(SeekFrom::Current(self.offset as i64))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/**
+ * Writes an nImage to an output which can't seek, like a pipe. Every part is read twice, so
+ * the part data must come from somewhere that gives the same data both times, like a file.
+ *
+ * First, call plan_part() for every part to calculate the image header without writing any
+ * output. Then call write_part() with the same data for every part in the same order, which
+ * writes the header followed by the part data. The data is hashed again as it's written, and
+ * write_part() fails if it doesn't match what plan_part() saw. Then call finish().
+ */
+pub struct StreamWriter<W: Write> {
+    inner: W,
+    header: ImageHeader,
+    // true once write_part() has been called, no more parts can be planned after that
+    writing: bool,
+    // index of the next part to write
+    next_index: usize,
+    // number of bytes written after the end of the header, or planned while planning
+    offset: u64,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// Start planning a current version image named name, which will be written to inner
+    pub fn new(inner: W, name: &str) -> io::Result<Self> {
+        Self::with_header(inner, ImageHeader::new(name))
+    }
+
+    /// Start planning an image with the name, version, and digest type from header
+    pub fn with_header(inner: W, header: ImageHeader) -> io::Result<Self> {
+        let header = new_header(header)?;
+        Ok(StreamWriter { inner, header, writing: false, next_index: 0, offset: 0 })
+    }
+
+    /// The image header, with all the parts planned so far
+    pub fn header(&self) -> &ImageHeader {
+        &self.header
+    }
+
+    /**
+     * Calculate the header of the next part by reading the data from reader until EOF,
+     * without writing anything. Returns the header of the new part.
+     */
+    pub fn plan_part<R: Read>(
+        &mut self,
+        reader: R,
+        ptype: PartType,
+        comp: CompMode,
+        options: &PartOptions,
+    ) -> io::Result<&PartHeader> {
+        if self.writing {
+            return Err(invalid_input("can't plan more parts after writing has started"));
+        }
+        check_new_part(&self.header, ptype)?;
+        let (size, hasher) =
+            write_part_data(reader, &mut io::sink(), comp, self.header.digest_type, options)?;
+        let part = PartHeader {
+            size,
+            offset: self.offset,
+            ptype,
+            comp,
+            xxh: hasher.xxh(),
+            digest: hasher.digest(),
+        };
+        self.offset += size + part_padding(size);
+        self.header.parts.push(part);
+        Ok(self.header.parts.last().unwrap())
+    }
+
+    /**
+     * Write the next planned part, reading the data from reader until EOF. The first call
+     * writes the image header before the part data. Fails if the data doesn't match the
+     * header calculated by plan_part().
+     */
+    pub fn write_part<R: Read>(&mut self, reader: R, options: &PartOptions) -> io::Result<()> {
+        if !self.writing {
+            self.header.write_to(&mut self.inner)?;
+            self.writing = true;
+            self.offset = 0;
+        }
+        let index = self.next_index;
+        let part = match self.header.parts.get(index) {
+            Some(part) => part.clone(),
+            None => return Err(invalid_input("all planned parts have already been written")),
+        };
+
+        let (size, hasher) =
+            write_part_data(reader, &mut self.inner, part.comp, self.header.digest_type, options)?;
+        if size != part.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("part {} changed size from {} to {} bytes", index, part.size, size),
+            ));
+        }
+        hasher.verify(&part).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("part {} changed: {}", index, e))
+        })?;
+
+        let padding = part_padding(size);
+        self.inner.write_zeros(padding as usize)?;
+        self.offset += size + padding;
+        self.next_index += 1;
+        Ok(())
+    }
+
+    /**
+     * Check that every planned part was written, and return the output.
+     */
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.next_index != self.header.parts.len() {
+            return Err(invalid_input(format!(
+                "only {} of {} planned parts were written",
+                self.next_index,
+                self.header.parts.len()
+            )));
+        }
+        if !self.writing {
+            // an image with no parts is just a header
+            self.header.write_to(&mut self.inner)?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::ImageReader;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_image() {
+        let mut writer = ImageWriter::new(Cursor::new(Vec::new()), "test image").unwrap();
+        let part = writer
+            .add_part(&b"boot"[..], PartType::BootImg, CompMode::None, &PartOptions::default())
+            .unwrap();
+        assert_eq!((part.offset, part.size), (0, 4));
+
+        let rootfs = vec![0x55u8; 100_000];
+        let options = PartOptions { zstd_level: Some(3), ..PartOptions::default() };
+        let part =
+            writer.add_part(&rootfs[..], PartType::Rootfs, CompMode::Zstd, &options).unwrap();
+        assert_eq!(part.offset, PART_ALIGN);
+        assert!(part.size < 1000);
+        assert!(writer.add_part(&b""[..], PartType::Rootfs, CompMode::None, &options).is_err());
+
+        let output = writer.finish().unwrap().into_inner();
+        let mut reader = ImageReader::new(Cursor::new(&output)).unwrap();
+        assert_eq!(reader.header().name, "test image");
+        assert_eq!(reader.header().parts.len(), 2);
+        let mut data = Vec::new();
+        reader.next_part().unwrap().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"boot");
+        data.clear();
+        reader
+            .next_part()
+            .unwrap()
+            .unwrap()
+            .decompressed()
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert!(data == rootfs);
+        reader.finish_part().unwrap();
+    }
+
+    #[test]
+    fn test_stream_writer() {
+        let parts: [(&[u8], PartType, CompMode); 3] = [
+            (b"boot", PartType::BootImg, CompMode::None),
+            (&[0xaa; 5000], PartType::Rootfs, CompMode::Zstd),
+            (b"more data", PartType::BootTar, CompMode::LibArchive),
+        ];
+        let options = PartOptions { zstd_level: Some(3), ..PartOptions::default() };
+        let opts = |comp| if comp == CompMode::Zstd { options.clone() } else { Default::default() };
+
+        // the same image is written either way
+        let mut writer = ImageWriter::new(Cursor::new(Vec::new()), "streamed").unwrap();
+        let mut stream = StreamWriter::new(Vec::new(), "streamed").unwrap();
+        for (data, ptype, comp) in parts.iter() {
+            writer.add_part(*data, *ptype, *comp, &opts(*comp)).unwrap();
+            stream.plan_part(*data, *ptype, *comp, &opts(*comp)).unwrap();
+        }
+        assert_eq!(stream.header(), writer.header());
+        for (data, _, comp) in parts.iter() {
+            stream.write_part(*data, &opts(*comp)).unwrap();
+        }
+        assert!(stream.finish().unwrap() == writer.finish().unwrap().into_inner());
+
+        // data that's different the second time around is an error
+        let mut stream = StreamWriter::new(Vec::new(), "streamed").unwrap();
+        let options = PartOptions::default();
+        stream.plan_part(&b"abc"[..], PartType::Rootfs, CompMode::None, &options).unwrap();
+        let err = stream.write_part(&b"abd"[..], &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("part 0 changed"), "{}", err);
+        let mut stream = StreamWriter::new(Vec::new(), "streamed").unwrap();
+        stream.plan_part(&b"abc"[..], PartType::Rootfs, CompMode::None, &options).unwrap();
+        assert!(stream.finish().is_err());
+    }
+}